@@ -0,0 +1,373 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// An arbitrary-precision signed integer.
+///
+/// The magnitude is stored as base 2^32 digits, least significant first, with
+/// no trailing zero digits. Zero has an empty magnitude and is never negative.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BigInt {
+    neg: bool,
+    mag: Vec<u32>,
+}
+
+/// The largest power of ten that fits in a digit, used for decimal conversion.
+const DECIMAL_BASE: u32 = 1_000_000_000;
+const DECIMAL_DIGITS: usize = 9;
+
+impl BigInt {
+    pub fn zero() -> BigInt {
+        BigInt { neg: false, mag: Vec::new() }
+    }
+
+    pub fn from_i64(n: i64) -> BigInt {
+        let abs = n.unsigned_abs();
+        BigInt::new(n < 0, vec![abs as u32, (abs >> 32) as u32])
+    }
+
+    /// Builds a number from a sign and a magnitude, normalizing both.
+    fn new(neg: bool, mut mag: Vec<u32>) -> BigInt {
+        trim(&mut mag);
+        BigInt { neg: neg && !mag.is_empty(), mag }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.mag.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.neg
+    }
+
+    /// The number of bits in the magnitude.
+    pub fn bits(&self) -> u64 {
+        match self.mag.last() {
+            Some(top) => self.mag.len() as u64 * 32 - top.leading_zeros() as u64,
+            None => 0,
+        }
+    }
+
+    /// The value as an `i64`, if it fits.
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.mag.len() > 2 {
+            return None;
+        }
+        let abs = self.mag.iter().rev().fold(0u64, |acc, &d| (acc << 32) | d as u64);
+        if self.neg {
+            if abs <= i64::MAX as u64 + 1 { Some((abs as i64).wrapping_neg()) } else { None }
+        } else if abs <= i64::MAX as u64 {
+            Some(abs as i64)
+        } else {
+            None
+        }
+    }
+
+    /// The value as an `i32`, if it fits.
+    pub fn to_i32(&self) -> Option<i32> {
+        self.to_i64().and_then(|n| {
+            if n >= i32::MIN as i64 && n <= i32::MAX as i64 { Some(n as i32) } else { None }
+        })
+    }
+
+    /// Converts to the nearest floating-point number, or an infinity.
+    pub fn to_f64(&self) -> f64 {
+        let abs = self.mag.iter().rev().fold(0.0, |acc, &d| acc * 4294967296.0 + d as f64);
+        if self.neg { -abs } else { abs }
+    }
+
+    pub fn neg(&self) -> BigInt {
+        BigInt::new(!self.neg, self.mag.clone())
+    }
+
+    pub fn add(&self, other: &BigInt) -> BigInt {
+        if self.neg == other.neg {
+            return BigInt::new(self.neg, add_mag(&self.mag, &other.mag));
+        }
+        match cmp_mag(&self.mag, &other.mag) {
+            Ordering::Less => BigInt::new(other.neg, sub_mag(&other.mag, &self.mag)),
+            _ => BigInt::new(self.neg, sub_mag(&self.mag, &other.mag)),
+        }
+    }
+
+    pub fn sub(&self, other: &BigInt) -> BigInt {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &BigInt) -> BigInt {
+        BigInt::new(self.neg != other.neg, mul_mag(&self.mag, &other.mag))
+    }
+
+    /// Divides, rounding the quotient towards zero, and returns the quotient
+    /// and the remainder (which has the sign of `self`, like `%` on
+    /// integers). Returns `None` if `other` is zero.
+    pub fn div_rem(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None;
+        }
+        let (q, r) = div_rem_mag(&self.mag, &other.mag);
+        Some((BigInt::new(self.neg != other.neg, q), BigInt::new(self.neg, r)))
+    }
+
+    pub fn pow(&self, mut exp: u32) -> BigInt {
+        let mut base = self.clone();
+        let mut acc = BigInt::from_i64(1);
+        while exp > 0 {
+            if exp & 1 == 1 {
+                acc = acc.mul(&base);
+            }
+            exp >>= 1;
+            if exp > 0 {
+                base = base.mul(&base);
+            }
+        }
+        acc
+    }
+}
+
+/// Drops trailing zero digits.
+fn trim(mag: &mut Vec<u32>) {
+    while mag.last() == Some(&0) {
+        mag.pop();
+    }
+}
+
+fn cmp_mag(a: &[u32], b: &[u32]) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (a, b) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut res = Vec::with_capacity(a.len() + 1);
+    let mut carry = 0u64;
+    for (i, &d) in a.iter().enumerate() {
+        let sum = d as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        res.push(sum as u32);
+        carry = sum >> 32;
+    }
+    if carry > 0 {
+        res.push(carry as u32);
+    }
+    res
+}
+
+/// Subtracts magnitudes, where `a` must be at least `b`.
+fn sub_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut res = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, &d) in a.iter().enumerate() {
+        let mut diff = d as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if diff < 0 {
+            diff += 1 << 32;
+            borrow = 1;
+        }
+        res.push(diff as u32);
+    }
+    trim(&mut res);
+    res
+}
+
+fn mul_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    if a.is_empty() || b.is_empty() {
+        return Vec::new();
+    }
+    let mut res = vec![0u32; a.len() + b.len()];
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, &y) in b.iter().enumerate() {
+            let cur = res[i + j] as u64 + x as u64 * y as u64 + carry;
+            res[i + j] = cur as u32;
+            carry = cur >> 32;
+        }
+        res[i + b.len()] = carry as u32;
+    }
+    trim(&mut res);
+    res
+}
+
+/// Divides a magnitude by a single digit, returning the quotient and the
+/// remainder.
+fn div_rem_small(a: &[u32], b: u32) -> (Vec<u32>, u32) {
+    let mut q = vec![0u32; a.len()];
+    let mut rem = 0u64;
+    for i in (0..a.len()).rev() {
+        let cur = (rem << 32) | a[i] as u64;
+        q[i] = (cur / b as u64) as u32;
+        rem = cur % b as u64;
+    }
+    trim(&mut q);
+    (q, rem as u32)
+}
+
+/// Long division of magnitudes, one bit at a time. `b` must not be zero.
+fn div_rem_mag(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if b.len() == 1 {
+        let (q, r) = div_rem_small(a, b[0]);
+        let mut r = vec![r];
+        trim(&mut r);
+        return (q, r);
+    }
+    if cmp_mag(a, b) == Ordering::Less {
+        return (Vec::new(), a.to_vec());
+    }
+    let mut q = vec![0u32; a.len()];
+    let mut r: Vec<u32> = Vec::new();
+    for i in (0..a.len() * 32).rev() {
+        // r = r * 2 + bit i of a
+        let mut carry = (a[i / 32] >> (i % 32)) & 1;
+        for d in r.iter_mut() {
+            let next = *d >> 31;
+            *d = (*d << 1) | carry;
+            carry = next;
+        }
+        if carry > 0 {
+            r.push(carry);
+        }
+        if cmp_mag(&r, b) != Ordering::Less {
+            r = sub_mag(&r, b);
+            q[i / 32] |= 1 << (i % 32);
+        }
+    }
+    trim(&mut q);
+    (q, r)
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.neg, other.neg) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_mag(&self.mag, &other.mag),
+            (true, true) => cmp_mag(&other.mag, &self.mag),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_zero() {
+            return f.write_str("0");
+        }
+        // Peel off nine decimal digits at a time, least significant first.
+        let mut chunks = Vec::new();
+        let mut mag = self.mag.clone();
+        while !mag.is_empty() {
+            let (q, r) = div_rem_small(&mag, DECIMAL_BASE);
+            chunks.push(r);
+            mag = q;
+        }
+        if self.neg {
+            f.write_str("-")?;
+        }
+        let mut chunks = chunks.iter().rev();
+        if let Some(first) = chunks.next() {
+            write!(f, "{}", first)?;
+        }
+        for chunk in chunks {
+            write!(f, "{:09}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for BigInt {
+    type Err = ();
+
+    /// Parses a decimal integer with an optional sign, e.g. `-12345678901`.
+    fn from_str(s: &str) -> Result<BigInt, ()> {
+        let (neg, digits) = match s.as_bytes().first() {
+            Some(&b'-') => (true, &s[1..]),
+            Some(&b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) {
+            return Err(());
+        }
+        let mut mag = Vec::new();
+        for chunk in digits.as_bytes().chunks(DECIMAL_DIGITS) {
+            // Both of these fit, as a chunk has at most nine digits.
+            let chunk = ::std::str::from_utf8(chunk).map_err(|_| ())?;
+            let value = u32::from_str(chunk).map_err(|_| ())?;
+            let scale = 10u32.pow(chunk.len() as u32);
+            mag = add_mag(&mul_mag(&mag, &[scale]), &[value]);
+            trim(&mut mag);
+        }
+        Ok(BigInt::new(neg, mag))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(s: &str) -> BigInt {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_display() {
+        for s in &["0", "1", "-1", "4294967296", "-18446744073709551616",
+                   "123456789012345678901234567890"] {
+            assert_eq!(big(s).to_string(), *s);
+        }
+        assert_eq!(big("-0").to_string(), "0");
+        assert_eq!(big("+0001000000000").to_string(), "1000000000");
+        assert!("".parse::<BigInt>().is_err());
+        assert!("-".parse::<BigInt>().is_err());
+        assert!("12a".parse::<BigInt>().is_err());
+    }
+
+    #[test]
+    fn test_from_to_i64() {
+        for &n in &[0, 1, -1, i32::MIN as i64, i64::MAX, i64::MIN] {
+            assert_eq!(BigInt::from_i64(n).to_i64(), Some(n));
+        }
+        assert_eq!(big("9223372036854775808").to_i64(), None);
+        assert_eq!(big("-2147483649").to_i32(), None);
+        assert_eq!(big("-2147483648").to_i32(), Some(i32::MIN));
+    }
+
+    #[test]
+    fn test_add_sub() {
+        assert_eq!(big("4294967295").add(&big("1")), big("4294967296"));
+        assert_eq!(big("-5").add(&big("3")), big("-2"));
+        assert_eq!(big("5").add(&big("-5")), BigInt::zero());
+        assert_eq!(big("18446744073709551616").sub(&big("1")), big("18446744073709551615"));
+        assert_eq!(big("1").sub(&big("18446744073709551616")), big("-18446744073709551615"));
+    }
+
+    #[test]
+    fn test_mul() {
+        assert_eq!(big("4294967296").mul(&big("4294967296")), big("18446744073709551616"));
+        assert_eq!(big("-123456789").mul(&big("987654321")), big("-121932631112635269"));
+        assert_eq!(big("0").mul(&big("-7")), BigInt::zero());
+    }
+
+    #[test]
+    fn test_div_rem() {
+        let (q, r) = big("121932631112635270").div_rem(&big("987654321")).unwrap();
+        assert_eq!((q, r), (big("123456789"), big("1")));
+        let (q, r) = big("-36893488147419103235").div_rem(&big("18446744073709551616")).unwrap();
+        assert_eq!((q, r), (big("-2"), big("-3")));
+        let (q, r) = big("7").div_rem(&big("-2")).unwrap();
+        assert_eq!((q, r), (big("-3"), big("1")));
+        assert!(big("7").div_rem(&BigInt::zero()).is_none());
+    }
+
+    #[test]
+    fn test_pow_cmp() {
+        assert_eq!(big("2").pow(100), big("1267650600228229401496703205376"));
+        assert_eq!(big("-3").pow(3), big("-27"));
+        assert_eq!(big("5").pow(0), big("1"));
+        assert!(big("-18446744073709551616") < big("-1"));
+        assert!(big("18446744073709551616") > big("4294967296"));
+        assert_eq!(big("2").pow(64).bits(), 65);
+    }
+}
@@ -0,0 +1,465 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
+
+use parser::{list_item, literal, Node, Session};
+use ops::{self, Operator};
+use rpn::{self, Elt, Observer, Op};
+
+/// How deeply user-defined words may call each other.
+const MAX_DEPTH: usize = 128;
+
+/// A compiled line or word body.
+pub type Program = Vec<Instr>;
+
+/// One instruction of a program. Jump targets are indices into the same
+/// program.
+#[derive(Debug)]
+pub enum Instr {
+    /// Push a literal value.
+    Push(Elt),
+    /// Evaluate a built-in operator on the stack.
+    Op(Op),
+    /// Run a user-defined word, looked up by name when it runs.
+    Call(String),
+    /// Define (or redefine) a word.
+    Define(String, Rc<Word>),
+    /// Switch how integer overflow is handled.
+    Mode(rpn::Arith),
+    Jump(usize),
+    /// Pop a boolean for `if`, and jump if it is false.
+    JumpUnless(usize),
+    /// Pop a boolean for `until`, and jump back if it is false.
+    Until(usize),
+    /// Pop the limit and start index of a `do` loop, and jump past the loop
+    /// if it runs no times.
+    Do(usize),
+    /// Step the innermost `do` loop's index, and jump back unless it has
+    /// reached the limit.
+    Loop(usize),
+    /// Push the innermost `do` loop's index.
+    Index,
+    Store(String),
+    Fetch(String),
+    Unset(String),
+    /// Apply any other operator from the session's registry.
+    Apply(Rc<dyn Operator>),
+}
+
+/// A user-defined word: the nodes it was parsed from, kept for saving, and
+/// the program they compile to.
+#[derive(Debug)]
+pub struct Word {
+    pub(crate) body: Rc<Vec<Node>>,
+    pub(crate) code: Program,
+}
+
+impl Word {
+    /// Compiles a word from its body.
+    pub(crate) fn new(body: Rc<Vec<Node>>) -> Word {
+        let code = compile(&body);
+        Word { body, code }
+    }
+
+    /// The body's source, as the tokens it was parsed from.
+    pub fn source(&self) -> String {
+        self.body.iter().map(|node| node.to_string()).collect::<Vec<_>>().join(" ")
+    }
+}
+
+/// Words are equal when they have the same source, which makes quotations
+/// compare by their code.
+impl PartialEq for Word {
+    fn eq(&self, other: &Word) -> bool {
+        self.source() == other.source()
+    }
+}
+
+impl PartialOrd for Word {
+    fn partial_cmp(&self, other: &Word) -> Option<Ordering> {
+        self.source().partial_cmp(&other.source())
+    }
+}
+
+/// Compiles parsed nodes into a program. Literals are parsed and operators
+/// looked up here, so running the program never looks at token text.
+pub fn compile(nodes: &[Node]) -> Program {
+    let mut code = Vec::new();
+    emit(nodes, &mut code);
+    code
+}
+
+fn emit(nodes: &[Node], code: &mut Program) {
+    for node in nodes {
+        match *node {
+            Node::Word(ref token) => {
+                let instr = match literal(token) {
+                    Some(elt) => Instr::Push(elt),
+                    None => Instr::Call(token.clone()),
+                };
+                code.push(instr);
+            },
+            Node::Op(ref op) => {
+                // Built-in operators have instructions of their own.
+                let instr = match op.builtin() {
+                    Some(op) => Instr::Op(op),
+                    None => Instr::Apply(op.clone()),
+                };
+                code.push(instr);
+            },
+            Node::List(_) | Node::Quote(_) => {
+                code.push(Instr::Push(constant(node).expect("lists only hold values")));
+            },
+            Node::Define(ref name, ref body) => {
+                code.push(Instr::Define(name.clone(), Rc::new(Word::new(body.clone()))));
+            },
+            Node::Mode(arith) => code.push(Instr::Mode(arith)),
+            Node::If(ref then, ref otherwise) => {
+                let branch = code.len();
+                code.push(Instr::JumpUnless(0));
+                emit(then, code);
+                if otherwise.is_empty() {
+                    code[branch] = Instr::JumpUnless(code.len());
+                } else {
+                    let skip = code.len();
+                    code.push(Instr::Jump(0));
+                    code[branch] = Instr::JumpUnless(code.len());
+                    emit(otherwise, code);
+                    code[skip] = Instr::Jump(code.len());
+                }
+            },
+            Node::BeginUntil(ref body) => {
+                let start = code.len();
+                emit(body, code);
+                code.push(Instr::Until(start));
+            },
+            Node::DoLoop(ref body) => {
+                let setup = code.len();
+                code.push(Instr::Do(0));
+                emit(body, code);
+                code.push(Instr::Loop(setup + 1));
+                code[setup] = Instr::Do(code.len());
+            },
+            Node::Index => code.push(Instr::Index),
+            Node::Store(ref name) => code.push(Instr::Store(name.clone())),
+            Node::Fetch(ref name) => code.push(Instr::Fetch(name.clone())),
+            Node::Unset(ref name) => code.push(Instr::Unset(name.clone())),
+        }
+    }
+}
+
+/// The value of a literal, list or quotation node, or `None` for any other
+/// node.
+pub fn constant(node: &Node) -> Option<Elt> {
+    match *node {
+        Node::Word(ref token) => literal(token),
+        Node::List(ref items) => {
+            let item = |node: &Node| match *node {
+                Node::Word(ref token) => list_item(token),
+                _ => constant(node),
+            };
+            items.iter().map(item).collect::<Option<Vec<Elt>>>().map(Elt::List)
+        },
+        Node::Quote(ref body) => Some(Elt::Quote(Rc::new(Word::new(body.clone())))),
+        _ => None,
+    }
+}
+
+/// Runs a program on a session, telling `observer` about each token. `depth`
+/// counts how many user-defined words are currently being run.
+pub fn run<O: Observer>(session: &mut Session, code: &[Instr], depth: usize, observer: &mut O)
+    -> rpn::Result<()>
+{
+    // The index and limit of each enclosing `do` loop.
+    let mut loops: Vec<(i32, i32)> = Vec::new();
+    let mut pc = 0;
+
+    while let Some(instr) = code.get(pc) {
+        pc += 1;
+        let token = instr.token();
+        if let Some(ref token) = token {
+            observer.before(token, depth, &session.stack)?;
+        }
+        match *instr {
+            Instr::Push(ref elt) => session.stack.push(elt.clone())?,
+            Instr::Op(op) if op.runs_quotes() => {
+                observer.before(op.token(), depth, &session.stack)?;
+                run_quotes(session, op, depth, observer)?;
+                observer.after(op.token(), depth, &session.stack)?;
+            },
+            Instr::Op(op) => session.stack.eval_observed(op, depth, observer)?,
+            Instr::Call(ref name) => {
+                let word = match session.words.get(name) {
+                    Some(word) => word.clone(),
+                    None => return Err(rpn::Error::Undefined(name.clone())),
+                };
+                if depth >= MAX_DEPTH {
+                    return Err(rpn::Error::Recursion(name.clone()));
+                }
+                run(session, &word.code, depth + 1, observer)?;
+            },
+            Instr::Define(ref name, ref word) => {
+                session.words.insert(name.clone(), word.clone());
+            },
+            Instr::Apply(ref op) => ops::apply(&**op, &mut session.stack)?,
+            Instr::Mode(arith) => session.stack.set_arith(arith),
+            Instr::Jump(target) => pc = target,
+            Instr::JumpUnless(target) => {
+                if !pop_bool(session, "if")? {
+                    pc = target;
+                }
+            },
+            Instr::Until(target) => {
+                if !pop_bool(session, "until")? {
+                    pc = target;
+                }
+            },
+            Instr::Do(end) => {
+                let (limit, start) = pop_bounds(session)?;
+                if start < limit {
+                    loops.push((start, limit));
+                } else {
+                    pc = end;
+                }
+            },
+            Instr::Loop(body) => {
+                let frame = loops.last_mut().expect("`loop` always follows a `do`");
+                frame.0 += 1;
+                if frame.0 < frame.1 {
+                    pc = body;
+                } else {
+                    loops.pop();
+                }
+            },
+            Instr::Index => {
+                let &(index, _) = loops.last().expect("`i` is only compiled inside loops");
+                session.stack.push(Elt::Int(index))?;
+            },
+            Instr::Store(ref name) => {
+                let elt = session.stack.pop().map_err(|_| {
+                    rpn::Error::Underflow(session.stack.fault(&format!("!{}", name)))
+                })?;
+                session.vars.store(name, elt);
+            },
+            Instr::Fetch(ref name) => {
+                let elt = session.vars.fetch(name)?;
+                session.stack.push(elt)?;
+            },
+            Instr::Unset(ref name) => {
+                session.vars.delete(name)?;
+            },
+        }
+        if let Some(ref token) = token {
+            observer.after(token, depth, &session.stack)?;
+        }
+    }
+    Ok(())
+}
+
+/// Evaluates an operator that runs quotations. Like other operators, one
+/// that fails before running anything leaves its operands on the stack.
+fn run_quotes<O: Observer>(session: &mut Session, op: Op, depth: usize, observer: &mut O) -> rpn::Result<()> {
+    let fault = |session: &Session| session.stack.fault(op.token());
+    if depth >= MAX_DEPTH {
+        return Err(rpn::Error::Recursion(op.token().to_string()));
+    }
+    let arity = match op {
+        Op::Call => 1,
+        Op::Fold => 3,
+        _ => 2,
+    };
+    let (quote, list, init) = {
+        let values = session.stack.values();
+        if values.len() < arity {
+            return Err(rpn::Error::Underflow(fault(session)));
+        }
+        // The operands, bottom first.
+        let args = &values[values.len() - arity..];
+        let quote = match args[arity - 1] {
+            Elt::Quote(ref quote) => quote.clone(),
+            _ => return Err(rpn::Error::Type(fault(session))),
+        };
+        let list = match (op, &args[0]) {
+            (Op::Call, _) => Vec::new(),
+            (_, Elt::List(list)) => list.clone(),
+            _ => return Err(rpn::Error::Type(fault(session))),
+        };
+        (quote, list, if op == Op::Fold { Some(args[1].clone()) } else { None })
+    };
+    for _ in 0..arity {
+        session.stack.pop()?;
+    }
+    if op == Op::Call {
+        return run(session, &quote.code, depth + 1, observer);
+    }
+
+    let mut acc = init;
+    let mut results = Vec::new();
+    for elt in list {
+        let base = session.stack.values().len();
+        if let Some(acc) = acc.take() {
+            session.stack.push(acc)?;
+        }
+        session.stack.push(elt.clone())?;
+        run(session, &quote.code, depth + 1, observer)?;
+        if session.stack.values().len() != base + 1 {
+            return Err(rpn::Error::Arity(fault(session)));
+        }
+        match (op, session.stack.peek()?) {
+            (Op::Filter, &Elt::Bool(_)) | (Op::Map, _) | (Op::Fold, _) => {},
+            _ => return Err(rpn::Error::Type(fault(session))),
+        }
+        match (op, session.stack.pop()?) {
+            (Op::Map, result) => results.push(result),
+            (Op::Filter, Elt::Bool(keep)) => {
+                if keep {
+                    results.push(elt);
+                }
+            },
+            (_, result) => acc = Some(result),
+        }
+    }
+    session.stack.push(match acc {
+        Some(acc) => acc,
+        None => Elt::List(results),
+    })
+}
+
+/// Pops the boolean that drives `op`.
+fn pop_bool(session: &mut Session, op: &str) -> rpn::Result<bool> {
+    match session.stack.values().last() {
+        None => Err(rpn::Error::Underflow(session.stack.fault(op))),
+        Some(&Elt::Bool(b)) => {
+            session.stack.pop()?;
+            Ok(b)
+        },
+        Some(_) => Err(rpn::Error::Type(session.stack.fault(op))),
+    }
+}
+
+/// Pops the limit and start index of a `do` loop.
+fn pop_bounds(session: &mut Session) -> rpn::Result<(i32, i32)> {
+    let values = session.stack.values();
+    if values.len() < 2 {
+        return Err(rpn::Error::Underflow(session.stack.fault("do")));
+    }
+    match (&values[values.len() - 2], &values[values.len() - 1]) {
+        (&Elt::Int(limit), &Elt::Int(start)) => {
+            session.stack.pop()?;
+            session.stack.pop()?;
+            Ok((limit, start))
+        },
+        _ => Err(rpn::Error::Type(session.stack.fault("do"))),
+    }
+}
+
+impl Instr {
+    /// The source token to tell observers about, if any. Built-in operators
+    /// tell them for themselves, and jumps, `loop` steps and definitions aren't
+    /// reported.
+    fn token(&self) -> Option<String> {
+        match *self {
+            Instr::Push(ref elt) => Some(elt.to_string()),
+            Instr::Call(ref name) => Some(name.clone()),
+            Instr::Mode(arith) => Some(format!("mode {}", arith)),
+            Instr::JumpUnless(_) => Some("if".to_string()),
+            Instr::Until(_) => Some("until".to_string()),
+            Instr::Do(_) => Some("do".to_string()),
+            Instr::Index => Some("i".to_string()),
+            Instr::Store(ref name) => Some(format!("!{}", name)),
+            Instr::Fetch(ref name) => Some(format!("@{}", name)),
+            Instr::Unset(ref name) => Some(format!("unset {}", name)),
+            Instr::Apply(ref op) => Some(op.name().to_string()),
+            Instr::Op(_) | Instr::Define(..) | Instr::Jump(_) | Instr::Loop(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instr::Push(ref elt) => write!(f, "push {}", elt),
+            Instr::Op(op) => write!(f, "op {}", op.token()),
+            Instr::Call(ref name) => write!(f, "call {}", name),
+            Instr::Define(ref name, _) => write!(f, "define {}", name),
+            Instr::Mode(arith) => write!(f, "mode {}", arith),
+            Instr::Jump(target) => write!(f, "jump {}", target),
+            Instr::JumpUnless(target) => write!(f, "jump-unless {}", target),
+            Instr::Until(target) => write!(f, "until {}", target),
+            Instr::Do(end) => write!(f, "do {}", end),
+            Instr::Loop(body) => write!(f, "loop {}", body),
+            Instr::Index => f.write_str("index"),
+            Instr::Store(ref name) => write!(f, "store {}", name),
+            Instr::Fetch(ref name) => write!(f, "fetch {}", name),
+            Instr::Unset(ref name) => write!(f, "unset {}", name),
+            Instr::Apply(ref op) => write!(f, "apply {}", op.name()),
+        }
+    }
+}
+
+/// Lists a program one numbered instruction per line. The bodies of words
+/// it defines are listed below their `define`, indented.
+pub fn disassemble(code: &[Instr]) -> String {
+    let mut out = String::new();
+    disassemble_into(code, 0, &mut out);
+    out
+}
+
+fn disassemble_into(code: &[Instr], indent: usize, out: &mut String) {
+    for (i, instr) in code.iter().enumerate() {
+        out.push_str(&format!("{}{:4}  {}\n", " ".repeat(indent), i, instr));
+        if let Instr::Define(_, ref word) = *instr {
+            disassemble_into(&word.code, indent + 6, out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bytecode::{compile, disassemble, run};
+    use ops::Registry;
+    use parser::{parse_line, Session};
+    use rpn::Elt;
+
+    fn listing(line: &str) -> String {
+        disassemble(&compile(&parse_line(&Registry::new(), &HashMap::new(), line).unwrap()))
+    }
+
+    #[test]
+    fn test_compile_literals() {
+        assert_eq!(listing("1 2.5 7/3 true + : sq dup * ; sq"),
+                   concat!("   0  push 1\n   1  push 2.5\n   2  push 7/3\n   3  push true\n   4  op +\n",
+                           "   5  define sq\n         0  op dup\n         1  op *\n   6  call sq\n"));
+    }
+
+    #[test]
+    fn test_compile_values() {
+        assert_eq!(listing("\"a b\" { 1 [ 2 ] } [ dup * ] call"),
+                   "   0  push \"a b\"\n   1  push { 1 [ 2 ] }\n   2  push [ dup * ]\n   3  op call\n");
+    }
+
+    #[test]
+    fn test_compile_control() {
+        assert_eq!(listing("if 1 else 2 then 3"),
+                   "   0  jump-unless 3\n   1  push 1\n   2  jump 4\n   3  push 2\n   4  push 3\n");
+        assert_eq!(listing("if 1 then"), "   0  jump-unless 2\n   1  push 1\n");
+        assert_eq!(listing("begin 1 until"), "   0  push 1\n   1  until 0\n");
+        assert_eq!(listing("3 0 do i loop"),
+                   "   0  push 3\n   1  push 0\n   2  do 5\n   3  index\n   4  loop 3\n");
+    }
+
+    #[test]
+    fn test_run() {
+        let mut session = Session::new();
+        let code = compile(&parse_line(&Registry::new(), &HashMap::new(), "0 5 0 do i + loop 0 0 do 99 loop").unwrap());
+        assert!(run(&mut session, &code, 0, &mut ()).is_ok());
+        assert_eq!(session.stack.values(), &[Elt::Int(10)]);
+
+        let code = compile(&parse_line(&Registry::new(), &HashMap::new(), "3 0 do 2 0 do i loop loop").unwrap());
+        assert!(run(&mut session, &code, 0, &mut ()).is_ok());
+        assert_eq!(session.stack.values(),
+                   &[Elt::Int(10), Elt::Int(0), Elt::Int(1), Elt::Int(0), Elt::Int(1), Elt::Int(0), Elt::Int(1)]);
+    }
+}
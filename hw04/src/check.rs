@@ -0,0 +1,960 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::BufRead;
+use std::iter::Peekable;
+use std::mem;
+use std::rc::Rc;
+use std::slice;
+use std::vec;
+
+use bytecode::Word;
+use infix;
+use ops::Registry;
+use parser::{infix_expr, literal, parse_line, tokenize, within, Node, ScriptError, Session, Token};
+use rpn::{self, Elt, Op};
+
+/// What is known, before running, about the type of a value.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Ty {
+    Int,
+    Bool,
+    Float,
+    Rational,
+    Big,
+    Str,
+    List,
+    Quote,
+    /// Some kind of number.
+    Num,
+    /// A string or a list.
+    Seq,
+    /// Anything at all.
+    Any,
+}
+
+impl Ty {
+    fn of(elt: &Elt) -> Ty {
+        match *elt {
+            Elt::Int(_) => Ty::Int,
+            Elt::Bool(_) => Ty::Bool,
+            Elt::Float(_) => Ty::Float,
+            Elt::Rational(_) => Ty::Rational,
+            Elt::Big(_) => Ty::Big,
+            Elt::Str(_) => Ty::Str,
+            Elt::List(_) => Ty::List,
+            Elt::Quote(_) => Ty::Quote,
+        }
+    }
+
+    fn is_num(self) -> bool {
+        matches!(self, Ty::Int | Ty::Float | Ty::Rational | Ty::Big | Ty::Num)
+    }
+
+    fn is_seq(self) -> bool {
+        matches!(self, Ty::Str | Ty::List | Ty::Seq)
+    }
+
+    /// The most precise type covering both, for a value that may be either.
+    fn join(self, other: Ty) -> Ty {
+        if self == other {
+            self
+        } else if self.is_num() && other.is_num() {
+            Ty::Num
+        } else if self.is_seq() && other.is_seq() {
+            Ty::Seq
+        } else {
+            Ty::Any
+        }
+    }
+
+    /// The type of a value known to be both, or `None` if nothing is.
+    fn meet(self, other: Ty) -> Option<Ty> {
+        match (self, other) {
+            (a, b) if a == b => Some(a),
+            (Ty::Any, t) | (t, Ty::Any) => Some(t),
+            (Ty::Num, t) | (t, Ty::Num) if t.is_num() => Some(t),
+            (Ty::Seq, t) | (t, Ty::Seq) if t.is_seq() => Some(t),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Ty::Int => "int",
+            Ty::Bool => "bool",
+            Ty::Float => "float",
+            Ty::Rational => "rat",
+            Ty::Big => "big",
+            Ty::Str => "str",
+            Ty::List => "list",
+            Ty::Quote => "quote",
+            Ty::Num => "num",
+            Ty::Seq => "seq",
+            Ty::Any => "any",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A value on the checker's stack.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Val {
+    ty: Ty,
+    /// Which of a definition's inputs this is an unchanged copy of, if any.
+    input: Option<usize>,
+    /// The value itself, for integers known before running.
+    konst: Option<i32>,
+}
+
+impl Val {
+    fn new(ty: Ty) -> Val {
+        Val { ty, input: None, konst: None }
+    }
+}
+
+/// What lies below the values the checker knows about.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Base {
+    /// Nothing: taking more is an underflow.
+    Empty,
+    /// The inputs of a definition, as many as it takes.
+    Inputs,
+    /// Nobody knows, so nothing more can be checked.
+    Unknown,
+}
+
+/// The checker's picture of the stack.
+#[derive(Clone, Debug)]
+struct State {
+    /// The values known about, bottom first.
+    vals: Vec<Val>,
+    base: Base,
+    /// The types required of the inputs taken from below, top first.
+    inputs: Vec<Ty>,
+    /// Set when inputs were consumed in a way an `Effect` can't describe.
+    opaque: bool,
+    /// Set once `quit` has run, after which nothing else does.
+    stopped: bool,
+}
+
+impl State {
+    fn new(base: Base) -> State {
+        State { vals: Vec::new(), base, inputs: Vec::new(), opaque: false, stopped: false }
+    }
+
+    /// Pops `n` values, top first, or returns `None` if that would underflow.
+    fn pop(&mut self, n: usize) -> Option<Vec<Val>> {
+        if self.base == Base::Empty && self.vals.len() < n {
+            return None;
+        }
+        let mut vals = Vec::new();
+        for _ in 0..n {
+            let val = match (self.vals.pop(), self.base) {
+                (Some(val), _) => val,
+                (None, Base::Inputs) => {
+                    self.inputs.push(Ty::Any);
+                    Val { ty: Ty::Any, input: Some(self.inputs.len() - 1), konst: None }
+                },
+                (None, _) => Val::new(Ty::Any),
+            };
+            vals.push(val);
+        }
+        Some(vals)
+    }
+
+    fn push(&mut self, vals: &[Val]) {
+        self.vals.extend_from_slice(vals);
+    }
+
+    /// The type of `val`, including what has been learned about its input.
+    fn ty(&self, val: &Val) -> Ty {
+        match val.input {
+            Some(k) => self.inputs[k],
+            None => val.ty,
+        }
+    }
+
+    /// Whether `val` may have type `need`. If it is an input, the input is
+    /// now known to need it.
+    fn require(&mut self, val: &Val, need: Ty) -> bool {
+        match self.ty(val).meet(need) {
+            Some(ty) => {
+                if let Some(k) = val.input {
+                    self.inputs[k] = ty;
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Gives up on knowing anything about the stack.
+    fn forget(&mut self) {
+        self.vals.clear();
+        self.base = Base::Unknown;
+    }
+
+    /// How many values running so far adds to the stack, overall.
+    fn net(&self) -> isize {
+        self.vals.len() as isize - self.inputs.len() as isize
+    }
+
+    /// Combines the states after two paths that may have been taken. A path
+    /// that quit goes no further, so only the other one counts.
+    fn merge(mut self, mut other: State) -> State {
+        if self.stopped {
+            return other;
+        }
+        if other.stopped {
+            return self;
+        }
+        if self.base == Base::Unknown || other.base == Base::Unknown || self.net() != other.net() {
+            self.forget();
+            return self;
+        }
+        // Make both paths take the same number of inputs, by having the one
+        // that took fewer take the rest and put them back.
+        let inputs = self.inputs.len().max(other.inputs.len());
+        for state in [&mut self, &mut other] {
+            let more = inputs - state.inputs.len();
+            if more > 0 {
+                let mut taken = state.pop(state.vals.len() + more).expect("inputs can't underflow");
+                taken.reverse();
+                state.push(&taken);
+            }
+        }
+        let vals = self.vals.iter().zip(&other.vals).map(|(a, b)| {
+            Val {
+                ty: self.ty(a).join(other.ty(b)),
+                input: if a.input == b.input { a.input } else { None },
+                konst: if a.konst == b.konst { a.konst } else { None },
+            }
+        }).collect();
+        let inputs = self.inputs.iter().zip(&other.inputs).map(|(a, b)| a.join(*b)).collect();
+        State { vals, base: self.base, inputs, opaque: self.opaque || other.opaque, stopped: false }
+    }
+}
+
+/// The stack effect of a line or word: the types of the values it takes and
+/// of the values it leaves.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Effect {
+    /// The types of the values taken, top first.
+    inputs: Vec<Ty>,
+    /// The values left, bottom first.
+    outputs: Vec<Val>,
+}
+
+impl Effect {
+    /// Makes the effect `( inputs -- outputs )`, listing both bottom first.
+    pub fn new(inputs: &[Ty], outputs: &[Ty]) -> Effect {
+        Effect {
+            inputs: inputs.iter().rev().cloned().collect(),
+            outputs: outputs.iter().map(|&ty| Val::new(ty)).collect(),
+        }
+    }
+
+    /// The types of the values taken, bottom first.
+    pub fn inputs(&self) -> Vec<Ty> {
+        self.inputs.iter().rev().cloned().collect()
+    }
+
+    /// The types of the values left, bottom first.
+    pub fn outputs(&self) -> Vec<Ty> {
+        self.outputs.iter().map(|val| val.input.map_or(val.ty, |k| self.inputs[k])).collect()
+    }
+}
+
+impl fmt::Display for Effect {
+    /// Writes the effect in the usual notation, e.g. `( num num -- num )`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("(")?;
+        for ty in self.inputs() {
+            write!(f, " {}", ty)?;
+        }
+        f.write_str(" --")?;
+        for ty in self.outputs() {
+            write!(f, " {}", ty)?;
+        }
+        f.write_str(" )")
+    }
+}
+
+/// Walks the tokens of a line alongside the nodes parsed from them, to find
+/// where each node came from.
+#[derive(Clone)]
+struct Cursor<'a> {
+    tokens: Peekable<vec::IntoIter<Token<'a>>>,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(line: &'a str) -> Cursor<'a> {
+        Cursor { tokens: tokenize(line).into_iter().peekable() }
+    }
+
+    fn next(&mut self) -> Token<'a> {
+        self.tokens.next().expect("nodes are parsed from these tokens")
+    }
+
+    fn next_is(&mut self, text: &str) -> bool {
+        self.tokens.peek().map(|t| t.text) == Some(text)
+    }
+
+    /// Skips the tokens `nodes` were parsed from.
+    fn skip(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            for _ in tokenize(&node.to_string()) {
+                self.next();
+            }
+        }
+    }
+
+    /// Skips a list or quotation, from its opening token to its closing one.
+    fn skip_group(&mut self) {
+        let mut open = 0;
+        loop {
+            match self.next().text {
+                "[" | "{" => open += 1,
+                "]" | "}" => open -= 1,
+                _ => {},
+            }
+            if open == 0 {
+                return;
+            }
+        }
+    }
+}
+
+fn check_error(token: Token, message: String) -> rpn::Error {
+    rpn::Error::Check(rpn::SyntaxError { token: token.text.to_string(), column: token.column, message })
+}
+
+/// Moves a check error in the translation of the infix expression `expr` to
+/// the `=` before it in `line`, since the token it names isn't in the line.
+fn at_infix(e: rpn::Error, line: &str, expr: &str) -> rpn::Error {
+    match e {
+        rpn::Error::Check(mut err) => {
+            err.column = line[..line.len() - expr.len()].chars().count() - 1;
+            rpn::Error::Check(err)
+        },
+        e => e,
+    }
+}
+
+fn underflow(token: Token) -> rpn::Error {
+    check_error(token, format!("`{}` would underflow the stack", token.text))
+}
+
+fn mistype(token: Token, need: Ty, found: Ty) -> rpn::Error {
+    check_error(token, format!("`{}` needs {} but would get {}", token.text, need, found))
+}
+
+/// Checks lines before they run, finding the first token that would
+/// underflow the stack or get a value of the wrong type. Only errors that
+/// are certain are reported: where the checker can't tell, it says nothing.
+pub struct Checker {
+    ops: Registry,
+    words: HashMap<String, Rc<Word>>,
+    /// The effects of the words worked out so far. `None` is a word whose
+    /// effect can't be described, or is still being worked out.
+    effects: HashMap<String, Option<Effect>>,
+    /// The variables each word whose effect is worked out may store to.
+    stores: HashMap<String, Vec<String>>,
+    /// The words whose effects were worked out, but which would certainly
+    /// fail, and why.
+    failures: HashMap<String, String>,
+    vars: HashMap<String, Ty>,
+    state: State,
+}
+
+impl Checker {
+    /// Starts checking from a session's current state, without changing it.
+    pub fn new(session: &Session) -> Checker {
+        let mut state = State::new(Base::Empty);
+        for elt in session.stack.values() {
+            let konst = if let Elt::Int(n) = *elt { Some(n) } else { None };
+            state.vals.push(Val { ty: Ty::of(elt), input: None, konst });
+        }
+        Checker {
+            ops: session.ops.clone(),
+            words: session.words.clone(),
+            effects: HashMap::new(),
+            stores: HashMap::new(),
+            failures: HashMap::new(),
+            vars: session.vars.list().map(|(name, elt)| (name.to_string(), Ty::of(elt))).collect(),
+            state,
+        }
+    }
+
+    /// Checks a line, and carries what it does on to the lines after it.
+    pub fn check_line(&mut self, line: &str) -> rpn::Result<()> {
+        if let Some(expr) = infix_expr(line) {
+            let rpn = infix::to_rpn(expr).map_err(|e| within(e, line, expr))?.join(" ");
+            return self.check_line(&rpn).map_err(|e| at_infix(e, line, expr));
+        }
+        let nodes = parse_line(&self.ops, &self.words, line)?;
+        let mut state = self.state.clone();
+        self.block(&mut state, &nodes, &mut Cursor::new(line))?;
+        self.state = state;
+        Ok(())
+    }
+
+    /// Works out the stack effect of a line on its own, or `None` if it
+    /// can't be described.
+    pub fn effect(&mut self, line: &str) -> rpn::Result<Option<Effect>> {
+        if let Some(expr) = infix_expr(line) {
+            let rpn = infix::to_rpn(expr).map_err(|e| within(e, line, expr))?.join(" ");
+            return self.effect(&rpn).map_err(|e| at_infix(e, line, expr));
+        }
+        let nodes = parse_line(&self.ops, &self.words, line)?;
+        self.effect_of(&nodes, &mut Cursor::new(line))
+    }
+
+    /// The types on the stack after the lines checked so far, bottom first,
+    /// or `None` if they aren't known.
+    pub fn types(&self) -> Option<Vec<Ty>> {
+        if self.state.base == Base::Unknown {
+            return None;
+        }
+        Some(self.state.vals.iter().map(|val| val.ty).collect())
+    }
+
+    fn effect_of(&mut self, nodes: &[Node], cursor: &mut Cursor) -> rpn::Result<Option<Effect>> {
+        let mut state = State::new(Base::Inputs);
+        self.block(&mut state, nodes, cursor)?;
+        if state.base == Base::Unknown || state.opaque || state.stopped {
+            return Ok(None);
+        }
+        Ok(Some(Effect { inputs: state.inputs, outputs: state.vals }))
+    }
+
+    /// Works out the effect of the body of the word `name`. The body runs
+    /// whenever the word is called, so it is checked knowing nothing about
+    /// the variables, and the variables it stores to are noted for `call`.
+    fn body_effect(&mut self, name: &str, body: &[Node], cursor: &mut Cursor) -> rpn::Result<Option<Effect>> {
+        let outer = mem::take(&mut self.vars);
+        let effect = self.effect_of(body, cursor);
+        let stored = mem::replace(&mut self.vars, outer);
+        self.stores.insert(name.to_string(), stored.into_keys().collect());
+        effect
+    }
+
+    /// The effect of a defined word, or `None` if it can't be described.
+    fn word_effect(&mut self, name: &str) -> Option<Effect> {
+        if let Some(effect) = self.effects.get(name) {
+            return effect.clone();
+        }
+        let word = self.words.get(name)?.clone();
+        // Until it is known, a word that calls itself does who knows what.
+        self.effects.insert(name.to_string(), None);
+        let text = word.source();
+        let effect = match self.body_effect(name, &word.body, &mut Cursor::new(&text)) {
+            Ok(effect) => effect,
+            Err(rpn::Error::Check(err)) => {
+                self.failures.insert(name.to_string(), err.message);
+                None
+            },
+            Err(_) => None,
+        };
+        self.effects.insert(name.to_string(), effect.clone());
+        effect
+    }
+
+    /// Checks a block that may not run at all, such as a branch or a loop
+    /// body. An error in it is only certain if it runs, so instead of
+    /// failing, nothing is known after it.
+    fn may_run(&mut self, state: &mut State, nodes: &[Node], cursor: &mut Cursor) -> rpn::Result<()> {
+        let mut ahead = cursor.clone();
+        match self.block(state, nodes, &mut ahead) {
+            Ok(()) => *cursor = ahead,
+            Err(rpn::Error::Check(_)) => {
+                cursor.skip(nodes);
+                state.forget();
+            },
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    fn block(&mut self, state: &mut State, nodes: &[Node], cursor: &mut Cursor) -> rpn::Result<()> {
+        for (i, node) in nodes.iter().enumerate() {
+            if state.stopped {
+                cursor.skip(&nodes[i..]);
+                return Ok(());
+            }
+            match *node {
+                Node::Word(_) => {
+                    let token = cursor.next();
+                    if let Some(elt) = literal(token.text) {
+                        let konst = if let Elt::Int(n) = elt { Some(n) } else { None };
+                        state.push(&[Val { ty: Ty::of(&elt), input: None, konst }]);
+                    } else {
+                        self.call(state, token)?;
+                    }
+                },
+                Node::Op(ref op) => {
+                    let token = cursor.next();
+                    match (op.builtin(), op.effect()) {
+                        (Some(builtin), _) => self.op(state, builtin, token)?,
+                        (None, Some(effect)) => self.apply(state, &effect, token)?,
+                        (None, None) => {
+                            state.pop(op.arity()).ok_or_else(|| underflow(token))?;
+                            state.forget();
+                        },
+                    }
+                },
+                Node::Define(ref name, ref body) => {
+                    // Defining a word doesn't run it, so its body is only
+                    // checked where it is called.
+                    cursor.skip(slice::from_ref(node));
+                    self.effects.clear();
+                    self.stores.clear();
+                    self.failures.clear();
+                    self.words.insert(name.clone(), Rc::new(Word::new(body.clone())));
+                },
+                Node::Mode(_) => {
+                    cursor.next();
+                    cursor.next();
+                },
+                Node::If(ref then, ref otherwise) => {
+                    let token = cursor.next();
+                    let cond = state.pop(1).ok_or_else(|| underflow(token))?;
+                    if !state.require(&cond[0], Ty::Bool) {
+                        return Err(mistype(token, Ty::Bool, state.ty(&cond[0])));
+                    }
+                    let mut other = state.clone();
+                    self.may_run(state, then, cursor)?;
+                    if cursor.next_is("else") {
+                        cursor.next();
+                        self.may_run(&mut other, otherwise, cursor)?;
+                    }
+                    cursor.next();
+                    *state = state.clone().merge(other);
+                },
+                Node::BeginUntil(ref body) => {
+                    cursor.next();
+                    let entry = state.clone();
+                    self.block(state, body, cursor)?;
+                    let token = cursor.next();
+                    if state.stopped {
+                        continue;
+                    }
+                    let cond = state.pop(1).ok_or_else(|| underflow(token))?;
+                    if !state.require(&cond[0], Ty::Bool) {
+                        return Err(mistype(token, Ty::Bool, state.ty(&cond[0])));
+                    }
+                    *state = state.clone().merge(entry);
+                },
+                Node::DoLoop(ref body) => {
+                    let token = cursor.next();
+                    let bounds = state.pop(2).ok_or_else(|| underflow(token))?;
+                    for bound in &bounds {
+                        if !state.require(bound, Ty::Int) {
+                            return Err(mistype(token, Ty::Int, state.ty(bound)));
+                        }
+                    }
+                    // The body may run no times at all.
+                    let entry = state.clone();
+                    self.may_run(state, body, cursor)?;
+                    cursor.next();
+                    *state = state.clone().merge(entry);
+                },
+                Node::Index => {
+                    cursor.next();
+                    state.push(&[Val::new(Ty::Int)]);
+                },
+                Node::Store(ref name) => {
+                    let token = cursor.next();
+                    let val = state.pop(1).ok_or_else(|| underflow(token))?;
+                    let ty = state.ty(&val[0]);
+                    self.vars.insert(name.clone(), ty);
+                },
+                Node::Fetch(ref name) => {
+                    cursor.next();
+                    let ty = self.vars.get(name).cloned().unwrap_or(Ty::Any);
+                    state.push(&[Val::new(ty)]);
+                },
+                Node::Unset(ref name) => {
+                    cursor.next();
+                    cursor.next();
+                    self.vars.remove(name);
+                },
+                Node::List(_) => {
+                    cursor.skip_group();
+                    state.push(&[Val::new(Ty::List)]);
+                },
+                Node::Quote(_) => {
+                    cursor.skip_group();
+                    state.push(&[Val::new(Ty::Quote)]);
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, state: &mut State, token: Token) -> rpn::Result<()> {
+        let effect = self.word_effect(token.text);
+        if let Some(message) = self.failures.get(token.text) {
+            return Err(check_error(token, format!("`{}` would fail: {}", token.text, message)));
+        }
+        // What the word stores could be anything, as far as what follows
+        // knows.
+        for var in self.stores.get(token.text).cloned().unwrap_or_default() {
+            self.vars.insert(var, Ty::Any);
+        }
+        match effect {
+            Some(effect) => self.apply(state, &effect, token),
+            None => {
+                state.forget();
+                Ok(())
+            },
+        }
+    }
+
+    /// Does to the stack what `effect` describes, for `token`.
+    fn apply(&self, state: &mut State, effect: &Effect, token: Token) -> rpn::Result<()> {
+        let args = state.pop(effect.inputs.len()).ok_or_else(|| underflow(token))?;
+        for (arg, &need) in args.iter().zip(&effect.inputs) {
+            if !state.require(arg, need) {
+                return Err(mistype(token, need, state.ty(arg)));
+            }
+        }
+        for out in &effect.outputs {
+            state.push(&[match out.input {
+                Some(k) => args[k],
+                None => *out,
+            }]);
+        }
+        Ok(())
+    }
+
+    /// Checks an operator, and does to the stack what it would.
+    fn op(&mut self, state: &mut State, op: Op, token: Token) -> rpn::Result<()> {
+        let need = |state: &mut State, val: &Val, ty: Ty| {
+            if state.require(val, ty) { Ok(()) } else { Err(mistype(token, ty, state.ty(val))) }
+        };
+        // Arguments, top first.
+        let x = state.pop(op.arity()).ok_or_else(|| underflow(token))?;
+        match op {
+            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Rem | Op::Pow => {
+                need(state, &x[0], Ty::Num)?;
+                need(state, &x[1], Ty::Num)?;
+                let float = state.ty(&x[0]) == Ty::Float || state.ty(&x[1]) == Ty::Float;
+                state.push(&[Val::new(if float { Ty::Float } else { Ty::Num })]);
+            },
+            Op::Eq | Op::Ne => state.push(&[Val::new(Ty::Bool)]),
+            Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+                need(state, &x[0], Ty::Num)?;
+                need(state, &x[1], Ty::Num)?;
+                state.push(&[Val::new(Ty::Bool)]);
+            },
+            Op::And | Op::Or => {
+                need(state, &x[0], Ty::Bool)?;
+                need(state, &x[1], Ty::Bool)?;
+                state.push(&[Val::new(Ty::Bool)]);
+            },
+            Op::Not => {
+                need(state, &x[0], Ty::Bool)?;
+                state.push(&[Val::new(Ty::Bool)]);
+            },
+            Op::Neg => {
+                let ty = match state.ty(&x[0]) {
+                    Ty::Int => Ty::Num,
+                    ty => ty,
+                };
+                state.push(&[Val::new(ty)]);
+            },
+            Op::ToInt | Op::ToFloat | Op::ToRational => {
+                need(state, &x[0], Ty::Num)?;
+                let ty = match op {
+                    Op::ToInt => Ty::Int,
+                    Op::ToFloat => Ty::Float,
+                    _ => Ty::Rational,
+                };
+                state.push(&[Val::new(ty)]);
+            },
+            Op::Rand => {
+                need(state, &x[0], Ty::Int)?;
+                state.push(&[Val::new(Ty::Int)]);
+            },
+            Op::Seed => need(state, &x[0], Ty::Int)?,
+            Op::Swap => state.push(&[x[0], x[1]]),
+            Op::Dup => state.push(&[x[0], x[0]]),
+            Op::Drop => {},
+            Op::Quit => state.stopped = true,
+            Op::Over => state.push(&[x[1], x[0], x[1]]),
+            Op::Rot => state.push(&[x[1], x[0], x[2]]),
+            Op::RotBack => state.push(&[x[0], x[2], x[1]]),
+            Op::Nip => state.push(&[x[0]]),
+            Op::Tuck => state.push(&[x[0], x[1], x[0]]),
+            Op::Pick | Op::Roll => {
+                need(state, &x[0], Ty::Int)?;
+                let n = match x[0].konst {
+                    Some(n) if n < 0 => return Err(underflow(token)),
+                    Some(n) => n as usize,
+                    None if op == Op::Pick => {
+                        state.push(&[Val::new(Ty::Any)]);
+                        return Ok(());
+                    },
+                    None => {
+                        state.forget();
+                        return Ok(());
+                    },
+                };
+                let mut below = state.pop(n + 1).ok_or_else(|| underflow(token))?;
+                let picked = if op == Op::Pick { below[n] } else { below.remove(n) };
+                below.reverse();
+                state.push(&below);
+                state.push(&[picked]);
+            },
+            Op::Concat => {
+                // Both must be strings, or both lists.
+                need(state, &x[0], Ty::Seq)?;
+                need(state, &x[1], Ty::Seq)?;
+                let (a, b) = (state.ty(&x[0]), state.ty(&x[1]));
+                let ty = a.meet(b).ok_or_else(|| mistype(token, a, b))?;
+                need(state, &x[0], ty)?;
+                need(state, &x[1], ty)?;
+                state.push(&[Val::new(ty)]);
+            },
+            Op::Length => {
+                need(state, &x[0], Ty::Seq)?;
+                state.push(&[Val::new(Ty::Int)]);
+            },
+            Op::Index => {
+                need(state, &x[0], Ty::Int)?;
+                need(state, &x[1], Ty::Seq)?;
+                let ty = if state.ty(&x[1]) == Ty::Str { Ty::Str } else { Ty::Any };
+                state.push(&[Val::new(ty)]);
+            },
+            Op::Call => {
+                // What a quotation does isn't known until it runs.
+                need(state, &x[0], Ty::Quote)?;
+                state.forget();
+            },
+            Op::Map | Op::Filter => {
+                need(state, &x[0], Ty::Quote)?;
+                need(state, &x[1], Ty::List)?;
+                state.push(&[Val::new(Ty::List)]);
+            },
+            Op::Fold => {
+                need(state, &x[0], Ty::Quote)?;
+                need(state, &x[2], Ty::List)?;
+                state.push(&[Val::new(Ty::Any)]);
+            },
+            Op::Clear => {
+                if state.base == Base::Inputs {
+                    state.opaque = true;
+                }
+                state.vals.clear();
+                state.base = Base::Empty;
+            },
+            Op::Depth => {
+                let konst = if state.base == Base::Empty { Some(state.vals.len() as i32) } else { None };
+                state.push(&[Val { ty: Ty::Int, input: None, konst }]);
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Checks a script the way `run_script` would run it on `session`, and
+/// returns the types it would leave on the stack, if they are known.
+pub fn check_script<R: BufRead>(session: &Session, input: R) -> Result<Option<Vec<Ty>>, ScriptError> {
+    let mut checker = Checker::new(session);
+    for (i, line) in input.lines().enumerate() {
+        let fail = |error| ScriptError { line: i + 1, error };
+        let line = line.map_err(|e| fail(rpn::Error::IO(e)))?;
+        if i == 0 && line.starts_with("#!") {
+            continue;
+        }
+        checker.check_line(&line).map_err(fail)?;
+        // As in `run_script`, nothing runs after `quit`.
+        if checker.state.stopped {
+            break;
+        }
+    }
+    Ok(checker.types())
+}
+
+#[cfg(test)]
+mod tests {
+    use check::{check_script, Checker, Effect, Ty};
+    use ops::Operator;
+    use parser::{run_script, Session};
+    use rpn::{self, Error, Op, Stack};
+
+    fn effect(line: &str) -> String {
+        Checker::new(&Session::new()).effect(line).unwrap().map_or("?".to_string(), |e| e.to_string())
+    }
+
+    fn check(line: &str) -> Result<(), (usize, String)> {
+        Checker::new(&Session::new()).check_line(line).map_err(|e| {
+            match e {
+                Error::Check(err) => (err.column, err.message),
+                e => panic!("unexpected error: {}", e),
+            }
+        })
+    }
+
+    #[test]
+    fn test_effect_ops() {
+        assert_eq!(effect("+"), "( num num -- num )");
+        assert_eq!(effect("1.5 *"), "( num -- float )");
+        assert_eq!(effect("<->"), "( any any -- any any )");
+        assert_eq!(effect("dup * 1 <"), "( num -- bool )");
+        assert_eq!(effect("not 2 3 rot"), "( bool -- int int bool )");
+        assert_eq!(effect("2 roll"), "( any any any -- any any any )");
+        assert_eq!(effect("= !x @x"), "( any any -- bool )");
+        assert_eq!(effect("clear"), "?");
+    }
+
+    #[test]
+    fn test_effect_control() {
+        assert_eq!(effect("if 1 else 2 then"), "( bool -- int )");
+        assert_eq!(effect("if 1 else 2.5 then"), "( bool -- num )");
+        assert_eq!(effect("if drop then"), "?");
+        assert_eq!(effect("if 1 2 then"), "?");
+        assert_eq!(effect("0 do i + loop"), "( any int -- any )");
+        assert_eq!(effect(": sq dup * ; sq sq"), "( num -- num )");
+    }
+
+    #[test]
+    fn test_check_line() {
+        assert_eq!(check("1 2 + dup *"), Ok(()));
+        assert_eq!(check("1 2 + +"), Err((6, "`+` would underflow the stack".to_string())));
+        assert_eq!(check("1 true +"), Err((7, "`+` needs num but would get bool".to_string())));
+        assert_eq!(check("1 if 2 then"), Err((2, "`if` needs bool but would get int".to_string())));
+        assert_eq!(check(": sq dup * ; sq"), Err((13, "`sq` would underflow the stack".to_string())));
+        assert_eq!(check(": f not ; 1 f"), Err((12, "`f` needs bool but would get int".to_string())));
+        assert_eq!(check("!x"), Err((0, "`!x` would underflow the stack".to_string())));
+        assert_eq!(check("1 2 3 2 pick 3 pick 9 pick"), Err((22, "`pick` would underflow the stack".to_string())));
+        assert_eq!(check(" =1 + true"), Err((1, "`+` needs num but would get bool".to_string())));
+        assert_eq!(check("begin true until drop"), Err((17, "`drop` would underflow the stack".to_string())));
+    }
+
+    #[test]
+    fn test_check_quotes() {
+        assert_eq!(effect("{ 1 } [ dup * ] map length"), "( -- int )");
+        assert_eq!(effect("concat"), "( seq seq -- seq )");
+        assert_eq!(effect("\"a b\" concat 0 index"), "( str -- str )");
+        assert_eq!(check("{ 1 } \"a\" concat"), Err((10, "`concat` needs str but would get list".to_string())));
+        assert_eq!(check("{ 1 } 2 map"), Err((8, "`map` needs quote but would get int".to_string())));
+        assert_eq!(check("[ + ] call"), Ok(()));
+    }
+
+    #[test]
+    fn test_check_unknown() {
+        // Where the checker can't tell, it doesn't complain.
+        assert_eq!(check(": f if 1 2 then ; true f + +"), Ok(()));
+        assert_eq!(check("3 0 do i loop + +"), Ok(()));
+        assert_eq!(check("@x not"), Ok(()));
+    }
+
+    #[test]
+    fn test_check_maybe_run() {
+        // Loop bodies may run no times, and branches may not be taken, so
+        // they only fail where they certainly run.
+        assert_eq!(check("0 0 do drop loop"), Ok(()));
+        assert_eq!(check("1 1 do + loop"), Ok(()));
+        assert_eq!(check("false if drop then"), Ok(()));
+        assert_eq!(check("true if 1 else drop then"), Ok(()));
+        assert_eq!(check("begin drop true until"), Err((6, "`drop` would underflow the stack".to_string())));
+        // A word is only checked where it is called.
+        assert_eq!(check(": f clear + ; 1"), Ok(()));
+        assert_eq!(check(": f clear + ; 1 f"),
+                   Err((16, "`f` would fail: `+` would underflow the stack".to_string())));
+    }
+
+    #[test]
+    fn test_check_quit() {
+        assert_eq!(check("1 quit + +"), Ok(()));
+        assert_eq!(check("true if quit then + +"), Err((18, "`+` would underflow the stack".to_string())));
+        assert_eq!(check("true if quit else 1 2 then +"), Ok(()));
+        assert_eq!(check_script(&Session::new(), "1\nquit\n+ +\n".as_bytes()).unwrap(), Some(vec![Ty::Int]));
+    }
+
+    #[test]
+    fn test_check_session() {
+        let mut session = Session::new();
+        assert!(run_script(&mut session, "1 true : sq dup * ;".as_bytes(), &mut Vec::new()).is_ok());
+        let mut checker = Checker::new(&session);
+        assert!(checker.check_line("drop sq").is_ok());
+        assert_eq!(checker.types(), Some(vec![Ty::Num]));
+        if let Err(Error::Check(_)) = checker.check_line("not") { } else { panic!(); }
+        // Checking leaves the session alone.
+        assert_eq!(session.stack.values().len(), 2);
+    }
+
+    #[test]
+    fn test_check_script() {
+        let script = "#!/usr/bin/env hw04\n: inc 1 + ;\n2 inc\ntrue inc\n";
+        let err = check_script(&Session::new(), script.as_bytes()).unwrap_err();
+        assert_eq!(err.line, 4);
+        if let Error::Check(ref err) = err.error {
+            assert_eq!(err.column, 5);
+        } else {
+            panic!();
+        }
+        assert_eq!(check_script(&Session::new(), "1 2\n<".as_bytes()).unwrap(), Some(vec![Ty::Bool]));
+    }
+
+    #[test]
+    fn test_check_stores_in_words() {
+        // Defining a word doesn't run its stores, and calling it may.
+        let script = "1 !x : setx true !x ;\n@x 1 +\n";
+        assert_eq!(check_script(&Session::new(), script.as_bytes()).unwrap(), Some(vec![Ty::Num]));
+        let script = "true !x : setx 1 !x ;\nsetx @x 1 +\n";
+        assert_eq!(check_script(&Session::new(), script.as_bytes()).unwrap(), Some(vec![Ty::Num]));
+        let script = "true !x : getx @x ;\n1 !x getx 1 +\n";
+        assert_eq!(check_script(&Session::new(), script.as_bytes()).unwrap(), Some(vec![Ty::Num]));
+        assert_eq!(check("1 !x : setx true !x ; setx @x 1 +"), Ok(()));
+    }
+
+    /// `hypot`, with a known effect, and `shuffle`, whose effect isn't.
+    struct Registered(&'static str);
+
+    impl Operator for Registered {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn arity(&self) -> usize {
+            2
+        }
+
+        fn effect(&self) -> Option<Effect> {
+            match self.0 {
+                "hypot" => Some(Effect::new(&[Ty::Num, Ty::Num], &[Ty::Float])),
+                _ => None,
+            }
+        }
+
+        fn apply(&self, stack: &mut Stack) -> rpn::Result<()> {
+            stack.eval(Op::Drop)
+        }
+    }
+
+    #[test]
+    fn test_check_registered() {
+        let mut session = Session::new();
+        session.ops.register(Registered("hypot"));
+        session.ops.register(Registered("shuffle"));
+        let mut checker = Checker::new(&session);
+        assert_eq!(checker.effect("hypot 2 *").unwrap().unwrap().to_string(), "( num num -- float )");
+        assert_eq!(checker.effect("shuffle").unwrap(), None);
+        if let Err(Error::Check(err)) = checker.check_line("true 1 hypot") {
+            assert_eq!(err.message, "`hypot` needs num but would get bool");
+        } else {
+            panic!();
+        }
+        if let Err(Error::Check(err)) = checker.check_line("1 shuffle") {
+            assert_eq!(err.message, "`shuffle` would underflow the stack");
+        } else {
+            panic!();
+        }
+        assert!(checker.check_line("1 2 shuffle +").is_ok());
+    }
+}
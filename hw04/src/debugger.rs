@@ -0,0 +1,124 @@
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+
+use rpn::{self, Observer, Stack};
+
+/// The commands understood at the debugger's prompt.
+pub const COMMANDS: &[&str] = &["step", "continue", "stack", "abort"];
+
+/// The REPL's debugger. It can trace a line, printing each token with the
+/// stack before and after it, and stop before tokens to ask what to do next:
+/// before every token while stepping, and at calls to the words in
+/// `breakpoints`.
+///
+/// While stopped, an empty line or `step` runs one more token, `continue`
+/// runs on to the next breakpoint, `stack` prints the stack and `abort`
+/// stops the line, which then fails like any other.
+pub struct Debugger<'a, W: 'a, F: 'a> {
+    trace: bool,
+    breakpoints: &'a BTreeSet<String>,
+    stepping: bool,
+    /// The stack before each traced token still running, innermost last.
+    before: Vec<String>,
+    output: &'a mut W,
+    /// Reads a line at the debugger's prompt, like the REPL's own.
+    read_line: &'a mut F,
+}
+
+impl<'a, W, F> Debugger<'a, W, F>
+    where W: Write, F: FnMut(&str, &[String], &mut W) -> io::Result<Option<String>>
+{
+    pub fn new(trace: bool, breakpoints: &'a BTreeSet<String>, stepping: bool, output: &'a mut W,
+               read_line: &'a mut F) -> Debugger<'a, W, F> {
+        Debugger { trace, breakpoints, stepping, before: Vec::new(), output, read_line }
+    }
+
+    /// Stops before `token` until told to go on.
+    fn pause(&mut self, token: &str, stack: &Stack) -> rpn::Result<()> {
+        writeln!(self.output, "  at `{}` with stack {}", token, stack)?;
+        let commands: Vec<String> = COMMANDS.iter().map(|c| c.to_string()).collect();
+        loop {
+            let line = match (self.read_line)("debug> ", &commands, self.output)? {
+                Some(line) => line,
+                None => {
+                    writeln!(self.output)?;
+                    return Err(rpn::Error::Stopped);
+                },
+            };
+            match line.trim() {
+                "" | "step" => {
+                    self.stepping = true;
+                    return Ok(());
+                },
+                "continue" => {
+                    self.stepping = false;
+                    return Ok(());
+                },
+                "stack" => writeln!(self.output, "  {}", stack)?,
+                "abort" => return Err(rpn::Error::Stopped),
+                other => {
+                    writeln!(self.output, "  unknown debugger command `{}`; try {}", other, COMMANDS.join(", "))?;
+                },
+            }
+        }
+    }
+}
+
+impl<'a, W, F> Observer for Debugger<'a, W, F>
+    where W: Write, F: FnMut(&str, &[String], &mut W) -> io::Result<Option<String>>
+{
+    fn before(&mut self, token: &str, _depth: usize, stack: &Stack) -> rpn::Result<()> {
+        if self.trace {
+            self.before.push(stack.to_string());
+        }
+        if self.stepping || self.breakpoints.contains(token) {
+            self.pause(token, stack)?;
+        }
+        Ok(())
+    }
+
+    /// Traces a token once it has run, so a word's line comes after the
+    /// lines for the tokens it ran, which are indented below it.
+    fn after(&mut self, token: &str, depth: usize, stack: &Stack) -> rpn::Result<()> {
+        if let Some(before) = self.before.pop() {
+            writeln!(self.output, "  {}{}  {} -> {}", "  ".repeat(depth), token, before, stack)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::io;
+
+    use debugger::Debugger;
+    use parser::{evaluate_line_with, Session};
+    use rpn::{Elt, Error};
+
+    #[test]
+    fn test_debugger() {
+        let mut session = Session::new();
+        let breakpoints = BTreeSet::new();
+        let mut output = Vec::new();
+        // Step twice, then abort.
+        let mut replies = vec!["abort", "", ""].into_iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let mut read_line = |_: &str, _: &[String], _: &mut Vec<u8>| -> io::Result<Option<String>> {
+            Ok(replies.pop())
+        };
+        {
+            let mut debugger = Debugger::new(true, &breakpoints, true, &mut output, &mut read_line);
+            if let Err(Error::Stopped) = evaluate_line_with(&mut session, "1 2 +", &mut debugger) {
+            } else {
+                panic!();
+            }
+        }
+        assert_eq!(session.stack.values(), &[] as &[Elt]);
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   concat!("  at `1` with stack []\n",
+                           "  1  [] -> [1]\n",
+                           "  at `2` with stack [1]\n",
+                           "  2  [1] -> [1 2]\n",
+                           "  at `+` with stack [1 2]\n"));
+    }
+}
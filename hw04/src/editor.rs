@@ -0,0 +1,591 @@
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str;
+
+/// How many lines of history are kept.
+const MAX_HISTORY: usize = 1000;
+
+/// A key press, decoded from the bytes a terminal sends.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    /// Delete the character under the cursor (Delete).
+    Delete,
+    /// Ctrl-D: like `Delete`, or the end of input on an empty line.
+    Eof,
+    /// Ctrl-C: abandon the line.
+    Cancel,
+    Left,
+    Right,
+    /// Back to the start of the previous word (Alt-B).
+    WordLeft,
+    /// On past the end of the next word (Alt-F).
+    WordRight,
+    Home,
+    End,
+    /// The previous line in the history (Up or Ctrl-P).
+    Up,
+    /// The next line in the history (Down or Ctrl-N).
+    Down,
+    /// Cut from the cursor to the end of the line (Ctrl-K).
+    KillEnd,
+    /// Cut from the start of the line to the cursor (Ctrl-U).
+    KillStart,
+    /// Cut the word before the cursor (Ctrl-W or Alt-Backspace).
+    KillWord,
+    /// Paste the last cut text (Ctrl-Y).
+    Yank,
+    /// Ctrl-L.
+    ClearScreen,
+    /// Anything else, which is ignored.
+    Unknown,
+}
+
+/// Reads one key press, or returns `None` at the end of input.
+pub fn read_key<R: Read>(input: &mut R) -> io::Result<Option<Key>> {
+    let byte = match read_byte(input)? {
+        Some(byte) => byte,
+        None => return Ok(None),
+    };
+    let key = match byte {
+        0x01 => Key::Home,
+        0x02 => Key::Left,
+        0x03 => Key::Cancel,
+        0x04 => Key::Eof,
+        0x05 => Key::End,
+        0x06 => Key::Right,
+        0x08 | 0x7f => Key::Backspace,
+        b'\t' => Key::Tab,
+        b'\n' | b'\r' => Key::Enter,
+        0x0b => Key::KillEnd,
+        0x0c => Key::ClearScreen,
+        0x0e => Key::Down,
+        0x10 => Key::Up,
+        0x15 => Key::KillStart,
+        0x17 => Key::KillWord,
+        0x19 => Key::Yank,
+        0x1b => read_escape(input)?,
+        0x20..=0x7e => Key::Char(byte as char),
+        0x80..=0xff => read_utf8(input, byte)?,
+        _ => Key::Unknown,
+    };
+    Ok(Some(key))
+}
+
+fn read_byte<R: Read>(input: &mut R) -> io::Result<Option<u8>> {
+    let mut buf = [0];
+    loop {
+        match input.read(&mut buf) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(buf[0])),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Decodes what follows an escape: an Alt chord, or a cursor key sequence
+/// like `ESC [ A` or `ESC [ 3 ~`.
+fn read_escape<R: Read>(input: &mut R) -> io::Result<Key> {
+    let key = match read_byte(input)? {
+        Some(b'b') => Key::WordLeft,
+        Some(b'f') => Key::WordRight,
+        Some(0x7f) => Key::KillWord,
+        Some(b'O') => {
+            match read_byte(input)? {
+                Some(b'H') => Key::Home,
+                Some(b'F') => Key::End,
+                Some(final_byte) => cursor_key(final_byte),
+                None => Key::Unknown,
+            }
+        },
+        Some(b'[') => {
+            let mut param = String::new();
+            loop {
+                match read_byte(input)? {
+                    Some(b) if b.is_ascii_digit() || b == b';' => param.push(b as char),
+                    Some(b'~') => {
+                        break match param.as_str() {
+                            "1" | "7" => Key::Home,
+                            "4" | "8" => Key::End,
+                            "3" => Key::Delete,
+                            _ => Key::Unknown,
+                        };
+                    },
+                    Some(b'H') => break Key::Home,
+                    Some(b'F') => break Key::End,
+                    Some(final_byte) => break cursor_key(final_byte),
+                    None => break Key::Unknown,
+                }
+            }
+        },
+        _ => Key::Unknown,
+    };
+    Ok(key)
+}
+
+fn cursor_key(final_byte: u8) -> Key {
+    match final_byte {
+        b'A' => Key::Up,
+        b'B' => Key::Down,
+        b'C' => Key::Right,
+        b'D' => Key::Left,
+        _ => Key::Unknown,
+    }
+}
+
+/// Reads the rest of a UTF-8 character that starts with `lead`.
+fn read_utf8<R: Read>(input: &mut R, lead: u8) -> io::Result<Key> {
+    let len = match lead {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return Ok(Key::Unknown),
+    };
+    let mut bytes = vec![lead];
+    for _ in 1..len {
+        match read_byte(input)? {
+            Some(b) => bytes.push(b),
+            None => return Ok(Key::Unknown),
+        }
+    }
+    Ok(match str::from_utf8(&bytes).ok().and_then(|s| s.chars().next()) {
+        Some(c) => Key::Char(c),
+        None => Key::Unknown,
+    })
+}
+
+/// The line being edited, with the cursor counted in characters.
+#[derive(Default, Debug)]
+struct Line {
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl Line {
+    fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    /// Replaces the whole line, leaving the cursor at its end.
+    fn set(&mut self, text: &str) {
+        self.chars = text.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    fn insert(&mut self, text: &str) {
+        for c in text.chars() {
+            self.chars.insert(self.cursor, c);
+            self.cursor += 1;
+        }
+    }
+
+    /// Removes the characters between `from` and `to`, returning them.
+    fn cut(&mut self, from: usize, to: usize) -> String {
+        self.cursor = from;
+        self.chars.drain(from..to).collect()
+    }
+
+    /// Where the word before the cursor starts.
+    fn word_start(&self) -> usize {
+        let mut i = self.cursor;
+        while i > 0 && self.chars[i - 1].is_whitespace() {
+            i -= 1;
+        }
+        while i > 0 && !self.chars[i - 1].is_whitespace() {
+            i -= 1;
+        }
+        i
+    }
+
+    /// Where the word after the cursor ends.
+    fn word_end(&self) -> usize {
+        let mut i = self.cursor;
+        while i < self.chars.len() && self.chars[i].is_whitespace() {
+            i += 1;
+        }
+        while i < self.chars.len() && !self.chars[i].is_whitespace() {
+            i += 1;
+        }
+        i
+    }
+
+    /// Completes the token before the cursor from `words`. A single match,
+    /// or a longer common prefix, is filled in; otherwise the candidates are
+    /// returned so they can be listed.
+    fn complete(&mut self, words: &[String]) -> Vec<String> {
+        let mut start = self.cursor;
+        while start > 0 && !self.chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        let prefix: String = self.chars[start..self.cursor].iter().collect();
+        let mut matches: Vec<&String> = words.iter().filter(|w| w.starts_with(&prefix)).collect();
+        matches.sort();
+        matches.dedup();
+
+        match matches.len() {
+            0 => Vec::new(),
+            1 => {
+                self.insert(&matches[0][prefix.len()..]);
+                self.insert(" ");
+                Vec::new()
+            },
+            _ => {
+                let common = matches[1..].iter().fold(matches[0].as_str(), |common, word| {
+                    let len = common.chars().zip(word.chars())
+                                    .take_while(|&(a, b)| a == b)
+                                    .map(|(a, _)| a.len_utf8())
+                                    .sum();
+                    &common[..len]
+                });
+                if common.len() > prefix.len() {
+                    self.insert(&common[prefix.len()..]);
+                    Vec::new()
+                } else {
+                    matches.into_iter().cloned().collect()
+                }
+            },
+        }
+    }
+}
+
+/// Puts the terminal into raw mode until dropped, when it goes back to the
+/// `saved` settings. It shells out to `stty` so that no platform bindings
+/// are needed.
+struct RawMode<'a> {
+    saved: &'a str,
+}
+
+impl<'a> RawMode<'a> {
+    fn enable(saved: &'a str) -> io::Result<RawMode<'a>> {
+        stty(&["raw", "-echo"])?;
+        Ok(RawMode { saved })
+    }
+}
+
+impl<'a> Drop for RawMode<'a> {
+    fn drop(&mut self) {
+        let _ = stty(&[self.saved]);
+    }
+}
+
+/// Runs `stty` on the terminal on stdin, returning what it printed.
+fn stty(args: &[&str]) -> io::Result<String> {
+    let out = Command::new("stty").args(args).stdin(Stdio::inherit()).output()?;
+    if !out.status.success() {
+        return Err(io::Error::other("stty failed"));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// The history file in the user's home directory, if there is one.
+pub fn default_history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".hw04_history"))
+}
+
+/// Reads lines from a terminal with Emacs-style editing keys, history and
+/// tab completion.
+#[derive(Default)]
+pub struct Editor {
+    history: Vec<String>,
+    /// Where each accepted line is appended, if anywhere.
+    path: Option<PathBuf>,
+    /// The terminal's settings outside raw mode, from `stty -g`, once they
+    /// have been read.
+    cooked: Option<String>,
+    /// The last text cut with Ctrl-K, Ctrl-U or Ctrl-W.
+    killed: String,
+}
+
+impl Editor {
+    /// Creates an editor with an empty history that is not saved.
+    pub fn new() -> Editor {
+        Editor::default()
+    }
+
+    /// Creates an editor whose history is loaded from, and saved to, `path`.
+    /// A missing or unreadable file just means no history yet.
+    pub fn with_history_file(path: PathBuf) -> Editor {
+        let mut editor = Editor::new();
+        let mut excess = 0;
+        if let Ok(file) = File::open(&path) {
+            editor.history = BufReader::new(file).lines().map_while(Result::ok).collect();
+            excess = editor.history.len().saturating_sub(MAX_HISTORY);
+            editor.history.drain(..excess);
+        }
+        editor.path = Some(path);
+        if excess > 0 {
+            editor.save_history();
+        }
+        editor
+    }
+
+    /// Adds a line to the history, unless it is blank or repeats the last
+    /// one.
+    pub fn add_history(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.last().map(|l| l.as_str()) == Some(line) {
+            return;
+        }
+        let full = self.history.len() == MAX_HISTORY;
+        if full {
+            self.history.remove(0);
+        }
+        self.history.push(line.to_string());
+        if full {
+            self.save_history();
+        } else if let Some(ref path) = self.path {
+            // Losing history is not worth interrupting the session for.
+            let _ = OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut file| writeln!(file, "{}", line));
+        }
+    }
+
+    /// Rewrites the history file with just the history kept, so that it
+    /// never grows past `MAX_HISTORY` lines.
+    fn save_history(&self) {
+        if let Some(ref path) = self.path {
+            let _ = File::create(path).and_then(|mut file| {
+                self.history.iter().try_for_each(|line| writeln!(file, "{}", line))
+            });
+        }
+    }
+
+    /// Shows `prompt` and reads a line from stdin, completing tokens from
+    /// `words`. Returns `None` at the end of input. Without a terminal on
+    /// stdin, this is a plain `read_line`.
+    ///
+    /// The terminal is only in raw mode while a line is read, so that output
+    /// and Ctrl-C work as usual while it runs. Its usual settings are read
+    /// once, the first time.
+    pub fn read_line(&mut self, prompt: &str, words: &[String]) -> io::Result<Option<String>> {
+        let stdin = io::stdin();
+        let mut output = io::stdout();
+        if self.cooked.is_none() && stdin.is_terminal() {
+            self.cooked = stty(&["-g"]).ok().map(|saved| saved.trim().to_string());
+        }
+        let cooked = self.cooked.clone();
+        let raw = match cooked {
+            Some(ref saved) => RawMode::enable(saved).ok(),
+            None => None,
+        };
+        if raw.is_none() {
+            write!(output, "{}", prompt)?;
+            output.flush()?;
+            let mut line = String::new();
+            return Ok(if stdin.lock().read_line(&mut line)? == 0 { None } else { Some(line) });
+        }
+        self.edit(&mut stdin.lock(), &mut output, prompt, words)
+    }
+
+    /// Edits a line, reading keys from `input` and drawing on `output`,
+    /// which must be a terminal in raw mode.
+    fn edit<R: Read, W: Write>(&mut self, input: &mut R, output: &mut W, prompt: &str, words: &[String])
+        -> io::Result<Option<String>>
+    {
+        let mut line = Line::default();
+        // Browsing the history: the entry shown, and the line being typed
+        // before browsing began.
+        let mut index = self.history.len();
+        let mut draft = String::new();
+
+        render(output, prompt, &line)?;
+        loop {
+            let key = match read_key(input)? {
+                Some(key) => key,
+                None if line.chars.is_empty() => return Ok(None),
+                None => Key::Enter,
+            };
+            match key {
+                Key::Enter => {
+                    write!(output, "\r\n")?;
+                    let text = line.text();
+                    self.add_history(&text);
+                    return Ok(Some(text));
+                },
+                Key::Eof if line.chars.is_empty() => return Ok(None),
+                Key::Cancel => {
+                    write!(output, "^C\r\n")?;
+                    line = Line::default();
+                    index = self.history.len();
+                },
+                Key::Char(c) => line.insert(c.encode_utf8(&mut [0; 4])),
+                Key::Backspace if line.cursor > 0 => {
+                    line.cut(line.cursor - 1, line.cursor);
+                },
+                Key::Delete | Key::Eof if line.cursor < line.chars.len() => {
+                    line.cut(line.cursor, line.cursor + 1);
+                },
+                Key::Left => line.cursor = line.cursor.saturating_sub(1),
+                Key::Right => line.cursor = (line.cursor + 1).min(line.chars.len()),
+                Key::WordLeft => line.cursor = line.word_start(),
+                Key::WordRight => line.cursor = line.word_end(),
+                Key::Home => line.cursor = 0,
+                Key::End => line.cursor = line.chars.len(),
+                Key::Up if index > 0 => {
+                    if index == self.history.len() {
+                        draft = line.text();
+                    }
+                    index -= 1;
+                    line.set(&self.history[index]);
+                },
+                Key::Down if index < self.history.len() => {
+                    index += 1;
+                    match self.history.get(index) {
+                        Some(text) => line.set(text),
+                        None => line.set(&draft),
+                    }
+                },
+                Key::KillEnd => self.killed = line.cut(line.cursor, line.chars.len()),
+                Key::KillStart => self.killed = line.cut(0, line.cursor),
+                Key::KillWord => {
+                    let start = line.word_start();
+                    self.killed = line.cut(start, line.cursor);
+                },
+                Key::Yank => line.insert(&self.killed),
+                Key::Tab => {
+                    let matches = line.complete(words);
+                    if !matches.is_empty() {
+                        write!(output, "\r\n{}\r\n", matches.join("  "))?;
+                    }
+                },
+                Key::ClearScreen => write!(output, "\x1b[H\x1b[2J")?,
+                _ => {},
+            }
+            render(output, prompt, &line)?;
+        }
+    }
+}
+
+/// Redraws the prompt and line, and puts the cursor in place.
+fn render<W: Write>(output: &mut W, prompt: &str, line: &Line) -> io::Result<()> {
+    write!(output, "\r{}{}\x1b[K\r", prompt, line.text())?;
+    let column = prompt.chars().count() + line.cursor;
+    if column > 0 {
+        write!(output, "\x1b[{}C", column)?;
+    }
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(bytes: &[u8]) -> Vec<Key> {
+        let mut input = bytes;
+        let mut keys = Vec::new();
+        while let Some(key) = read_key(&mut input).unwrap() {
+            keys.push(key);
+        }
+        keys
+    }
+
+    /// Edits a line typed as `bytes`, returning what was entered.
+    fn edit(editor: &mut Editor, bytes: &[u8], words: &[&str]) -> Option<String> {
+        let words: Vec<String> = words.iter().map(|w| w.to_string()).collect();
+        let mut output = Vec::new();
+        editor.edit(&mut &bytes[..], &mut output, "> ", &words).unwrap()
+    }
+
+    #[test]
+    fn test_read_key() {
+        assert_eq!(keys(b"a\x1b[A\x1b[B\x1bOC\x1b[D\x1b[3~\x1b[1~\x1b[F"),
+                   vec![Key::Char('a'), Key::Up, Key::Down, Key::Right, Key::Left, Key::Delete,
+                        Key::Home, Key::End]);
+        assert_eq!(keys(b"\x01\x05\x0b\x15\x17\x19\x7f\t\r\x1bb\x1bf\x1b[5~"),
+                   vec![Key::Home, Key::End, Key::KillEnd, Key::KillStart, Key::KillWord, Key::Yank,
+                        Key::Backspace, Key::Tab, Key::Enter, Key::WordLeft, Key::WordRight,
+                        Key::Unknown]);
+        assert_eq!(keys("é→".as_bytes()), vec![Key::Char('é'), Key::Char('→')]);
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut editor = Editor::new();
+        assert_eq!(edit(&mut editor, b"12\x7f3\r", &[]), Some("13".to_string()));
+        assert_eq!(edit(&mut editor, b"bc\x01a\x05d\x1b[D\x1b[Dx\r", &[]), Some("abxcd".to_string()));
+        assert_eq!(edit(&mut editor, b"1 2 +\x1bb\x1bb\x0b\x01\x19\r", &[]), Some("2 +1 ".to_string()));
+        assert_eq!(edit(&mut editor, b"one two\x17\x01\x19 \r", &[]), Some("two one ".to_string()));
+        assert_eq!(edit(&mut editor, b"oops\x03ok\r", &[]), Some("ok".to_string()));
+        assert_eq!(edit(&mut editor, b"\x04", &[]), None);
+        assert_eq!(edit(&mut editor, b"", &[]), None);
+        assert_eq!(edit(&mut editor, b"ab\x01\x04\r", &[]), Some("b".to_string()));
+    }
+
+    #[test]
+    fn test_history() {
+        let mut editor = Editor::new();
+        edit(&mut editor, b"1 2 +\r", &[]);
+        edit(&mut editor, b"\r", &[]);
+        edit(&mut editor, b"3 *\r", &[]);
+        edit(&mut editor, b"3 *\r", &[]);
+        assert_eq!(editor.history, ["1 2 +".to_string(), "3 *".to_string()]);
+
+        assert_eq!(edit(&mut editor, b"\x1b[A\x1b[A\r", &[]), Some("1 2 +".to_string()));
+        assert_eq!(edit(&mut editor, b"4\x10\x10\x10\x0e\x0e\x0e\r", &[]), Some("4".to_string()));
+        assert_eq!(edit(&mut editor, b"\x10 dup\r", &[]), Some("4 dup".to_string()));
+    }
+
+    #[test]
+    fn test_history_file() {
+        let path = env::temp_dir().join(format!("hw04-test-{}.history", ::std::process::id()));
+        let mut editor = Editor::with_history_file(path.clone());
+        edit(&mut editor, b"1 2 +\r", &[]);
+        edit(&mut editor, b"dup *\r", &[]);
+
+        let editor = Editor::with_history_file(path.clone());
+        ::std::fs::remove_file(&path).unwrap();
+        assert_eq!(editor.history, ["1 2 +".to_string(), "dup *".to_string()]);
+    }
+
+    #[test]
+    fn test_history_file_limit() {
+        let path = env::temp_dir().join(format!("hw04-test-{}-limit.history", ::std::process::id()));
+        let lines: Vec<String> = (0..MAX_HISTORY + 5).map(|n| n.to_string()).collect();
+        ::std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+        let read = || ::std::fs::read_to_string(&path).unwrap().lines().map(String::from).collect::<Vec<_>>();
+
+        // The file is cut down to the lines kept, on opening and as lines
+        // are added.
+        let mut editor = Editor::with_history_file(path.clone());
+        assert_eq!(read(), &lines[5..]);
+        edit(&mut editor, b"dup *\r", &[]);
+        let saved = read();
+        ::std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved.len(), MAX_HISTORY);
+        assert_eq!(saved[0], "6");
+        assert_eq!(saved[MAX_HISTORY - 1], "dup *");
+        assert_eq!(editor.history, saved);
+    }
+
+    #[test]
+    fn test_complete() {
+        let mut editor = Editor::new();
+        let words = ["dup", "drop", "depth", "swap"];
+        assert_eq!(edit(&mut editor, b"1 du\t\r", &words), Some("1 dup ".to_string()));
+        assert_eq!(edit(&mut editor, b"sw\tx\r", &words), Some("swap x".to_string()));
+        assert_eq!(edit(&mut editor, b"dr\x01\x05\t\r", &words), Some("drop ".to_string()));
+        assert_eq!(edit(&mut editor, b"zz\t\r", &words), Some("zz".to_string()));
+
+        let mut output = Vec::new();
+        let words: Vec<String> = words.iter().map(|w| w.to_string()).collect();
+        let line = editor.edit(&mut &b"d\t\r"[..], &mut output, "> ", &words).unwrap();
+        assert_eq!(line, Some("d".to_string()));
+        assert!(String::from_utf8(output).unwrap().contains("\r\ndepth  drop  dup\r\n"));
+    }
+
+    #[test]
+    fn test_complete_common_prefix() {
+        let mut line = Line::default();
+        line.insert("de");
+        let words = vec!["define".to_string(), "definite".to_string()];
+        assert!(line.complete(&words).is_empty());
+        assert_eq!(line.text(), "defin");
+        assert_eq!(line.complete(&words), words);
+    }
+}
@@ -0,0 +1,299 @@
+use std::iter::Peekable;
+use std::vec;
+
+use parser::{literal, syntax, var_name, Token, MAX_NESTING};
+use rpn;
+
+/// Infix operators, the RPN operators they translate to, and how tightly
+/// they bind. All are left-associative except `**`.
+const BINARY: &[(&str, &str, u8)] = &[
+    ("||", "or", 1), ("or", "or", 1),
+    ("&&", "and", 2), ("and", "and", 2),
+    ("==", "=", 3), ("!=", "!=", 3),
+    ("<", "<", 4), ("<=", "<=", 4), (">", ">", 4), (">=", ">=", 4),
+    ("+", "+", 5), ("-", "-", 5),
+    ("*", "*", 6), ("/", "/", 6), ("%", "%", 6),
+    ("**", "**", 8),
+];
+
+/// How tightly prefix `-` and `!` bind: looser than `**`, so that `-x ** 2`
+/// is `-(x ** 2)`, and tighter than everything else.
+const PREFIX: u8 = 7;
+
+/// Functions of one argument, and the RPN operators they translate to.
+const FUNCTIONS: &[(&str, &str)] = &[("int", ">int"), ("float", ">float"), ("rat", ">rat"), ("rand", "#")];
+
+/// Symbols, longest first so that `**` is not read as two `*`.
+const SYMBOLS: &[&str] = &["**", "==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "<", ">",
+                           "!", "(", ")"];
+
+/// Splits an infix expression into numbers, names and symbols, counting
+/// columns in characters.
+fn tokenize(expr: &str) -> rpn::Result<Vec<Token<'_>>> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.char_indices().enumerate().peekable();
+    while let Some(&(column, (i, c))) = chars.peek() {
+        let rest = &expr[i..];
+        let len = if c.is_whitespace() {
+            chars.next();
+            continue;
+        } else if c.is_ascii_digit() || c == '.' {
+            number_len(rest)
+        } else if c.is_alphabetic() || c == '_' {
+            rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len())
+        } else {
+            match SYMBOLS.iter().find(|s| rest.starts_with(*s)) {
+                Some(s) => s.len(),
+                None => {
+                    let token = Token { text: &rest[..c.len_utf8()], column };
+                    return Err(syntax(token, format!("unexpected `{}`", token.text)));
+                },
+            }
+        };
+        tokens.push(Token { text: &rest[..len], column });
+        while chars.peek().is_some_and(|&(_, (j, _))| j < i + len) {
+            chars.next();
+        }
+    }
+    Ok(tokens)
+}
+
+/// The length of the number at the start of `s`, e.g. `2.5e-3`.
+fn number_len(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    let mut len = 0;
+    while len < bytes.len() && (bytes[len].is_ascii_digit() || bytes[len] == b'.') {
+        len += 1;
+    }
+    if len < bytes.len() && (bytes[len] == b'e' || bytes[len] == b'E') {
+        let mut exp = len + 1;
+        if exp < bytes.len() && (bytes[exp] == b'+' || bytes[exp] == b'-') {
+            exp += 1;
+        }
+        if exp < bytes.len() && bytes[exp].is_ascii_digit() {
+            len = exp;
+            while len < bytes.len() && bytes[len].is_ascii_digit() {
+                len += 1;
+            }
+        }
+    }
+    len
+}
+
+/// Translates an infix expression such as `(3 + 4) * -x == 7` into RPN
+/// tokens, here `3 4 + @x ~ * 7 =`. Names are variables, except `true`,
+/// `false`, and the functions `int`, `float`, `rat` and `rand` when they are
+/// called. Syntax errors give columns in `expr`.
+pub fn to_rpn(expr: &str) -> rpn::Result<Vec<String>> {
+    Ok(translate(expr)?.tokens.into_iter().map(|(text, _)| text).collect())
+}
+
+/// Translates an infix expression like `to_rpn`, keeping track of where
+/// each RPN token came from.
+pub fn translate(expr: &str) -> rpn::Result<Rpn> {
+    let mut parser = Parser {
+        tokens: tokenize(expr)?.into_iter().peekable(),
+        end: expr.trim_end().chars().count(),
+        rpn: Vec::new(),
+        depth: 0,
+    };
+    parser.expr(0)?;
+    if let Some(token) = parser.tokens.next() {
+        return Err(syntax(token, format!("unexpected `{}`", token.text)));
+    }
+    Ok(Rpn { tokens: parser.rpn })
+}
+
+/// The translation of an infix expression into RPN.
+pub struct Rpn {
+    /// Each RPN token, and the column of the token in the expression that
+    /// it came from.
+    tokens: Vec<(String, usize)>,
+}
+
+impl Rpn {
+    /// The RPN as a line.
+    pub fn text(&self) -> String {
+        self.tokens.iter().map(|(text, _)| text.as_str()).collect::<Vec<_>>().join(" ")
+    }
+
+    /// Moves an error in running `text` to the token of the expression that
+    /// the token it names came from.
+    pub fn locate(&self, e: rpn::Error) -> rpn::Error {
+        let locate = |err: &mut rpn::SyntaxError| {
+            let mut start = 0;
+            for &(ref text, column) in &self.tokens {
+                start += text.chars().count() + 1;
+                if err.column < start {
+                    err.column = column;
+                    return;
+                }
+            }
+        };
+        match e {
+            rpn::Error::Syntax(mut err) => {
+                locate(&mut err);
+                rpn::Error::Syntax(err)
+            },
+            rpn::Error::Check(mut err) => {
+                locate(&mut err);
+                rpn::Error::Check(err)
+            },
+            e => e,
+        }
+    }
+}
+
+/// A precedence-climbing parser, which writes out RPN as it goes.
+struct Parser<'a> {
+    tokens: Peekable<vec::IntoIter<Token<'a>>>,
+    /// The column just past the expression, for errors at its end.
+    end: usize,
+    rpn: Vec<(String, usize)>,
+    /// How many expressions enclose the current one.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Parses an expression whose binary operators bind at least as tightly
+    /// as `min`.
+    fn expr(&mut self, min: u8) -> rpn::Result<()> {
+        if self.depth == MAX_NESTING {
+            let token = self.tokens.peek().cloned().unwrap_or(Token { text: "", column: self.end });
+            return Err(syntax(token, format!("expression nested more than {} deep", MAX_NESTING)));
+        }
+        self.depth += 1;
+        let res = self.climb(min);
+        self.depth -= 1;
+        res
+    }
+
+    /// Does the work of `expr`, once it is known not to be nested too deeply.
+    fn climb(&mut self, min: u8) -> rpn::Result<()> {
+        self.operand()?;
+        loop {
+            let op = match self.tokens.peek() {
+                Some(token) => BINARY.iter().find(|op| op.0 == token.text),
+                None => None,
+            };
+            let &(_, rpn_op, power) = match op {
+                Some(op) if op.2 >= min => op,
+                _ => return Ok(()),
+            };
+            let token = self.tokens.next().expect("just peeked");
+            // A right-associative operator takes operators as loose as
+            // itself on its right.
+            self.expr(if rpn_op == "**" { power } else { power + 1 })?;
+            self.rpn.push((rpn_op.to_string(), token.column));
+        }
+    }
+
+    /// Parses a value, with any prefix operators.
+    fn operand(&mut self) -> rpn::Result<()> {
+        let token = match self.tokens.next() {
+            Some(token) => token,
+            None => {
+                let end = Token { text: "", column: self.end };
+                return Err(syntax(end, "expected a value at the end".to_string()));
+            },
+        };
+        match token.text {
+            "-" | "!" | "not" => {
+                self.expr(PREFIX)?;
+                let op = if token.text == "-" { "~" } else { "not" };
+                self.rpn.push((op.to_string(), token.column));
+            },
+            "(" => {
+                self.expr(0)?;
+                self.close(token)?;
+            },
+            text if text.starts_with(|c: char| c.is_ascii_digit() || c == '.') => {
+                if literal(text).is_none() {
+                    return Err(syntax(token, format!("bad number `{}`", text)));
+                }
+                self.rpn.push((text.to_string(), token.column));
+            },
+            "true" | "false" => self.rpn.push((token.text.to_string(), token.column)),
+            text if text.starts_with(|c: char| c.is_alphabetic() || c == '_') => {
+                let function = FUNCTIONS.iter().find(|f| f.0 == text);
+                match function {
+                    Some(&(_, op)) if self.tokens.peek().map(|t| t.text) == Some("(") => {
+                        let open = self.tokens.next().expect("just peeked");
+                        self.expr(0)?;
+                        self.close(open)?;
+                        self.rpn.push((op.to_string(), token.column));
+                    },
+                    _ => self.rpn.push((format!("@{}", var_name(token, text)?), token.column)),
+                }
+            },
+            text => return Err(syntax(token, format!("expected a value, found `{}`", text))),
+        }
+        Ok(())
+    }
+
+    /// Expects the `)` closing `open`.
+    fn close(&mut self, open: Token) -> rpn::Result<()> {
+        match self.tokens.next() {
+            Some(ref token) if token.text == ")" => Ok(()),
+            Some(token) => Err(syntax(token, format!("expected `)`, found `{}`", token.text))),
+            None => Err(syntax(open, "unclosed `(`".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use infix::to_rpn;
+    use parser::MAX_NESTING;
+    use rpn::Error;
+
+    fn rpn(expr: &str) -> String {
+        to_rpn(expr).unwrap().join(" ")
+    }
+
+    fn error(expr: &str) -> (usize, String) {
+        match to_rpn(expr) {
+            Err(Error::Syntax(err)) => (err.column, err.message),
+            res => panic!("expected a syntax error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(rpn("(3 + 4) * -x == 7"), "3 4 + @x ~ * 7 =");
+        assert_eq!(rpn("1 + 2 * 3"), "1 2 3 * +");
+        assert_eq!(rpn("1 < 2 && 2 < 3 || !b"), "1 2 < 2 3 < and @b not or");
+        assert_eq!(rpn("-2 ** 2"), "2 2 ** ~");
+        assert_eq!(rpn("2 ** -1"), "2 1 ~ **");
+        assert_eq!(rpn("x % 3 != 0 and not done"), "@x 3 % 0 != @done not and");
+    }
+
+    #[test]
+    fn test_associativity() {
+        assert_eq!(rpn("10 - 4 - 3"), "10 4 - 3 -");
+        assert_eq!(rpn("64 / 4 / 2"), "64 4 / 2 /");
+        assert_eq!(rpn("2 ** 3 ** 2"), "2 3 2 ** **");
+    }
+
+    #[test]
+    fn test_values() {
+        assert_eq!(rpn("2.5e-3*x_1"), "2.5e-3 @x_1 *");
+        assert_eq!(rpn("int(7 / 2.0) + rand(6)"), "7 2.0 / >int 6 # +");
+        assert_eq!(rpn("int + true"), "@int true +");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error("1 +"), (3, "expected a value at the end".to_string()));
+        assert_eq!(error("(1 + 2"), (0, "unclosed `(`".to_string()));
+        assert_eq!(error("1 2"), (2, "unexpected `2`".to_string()));
+        assert_eq!(error("1 $ 2"), (2, "unexpected `$`".to_string()));
+        assert_eq!(error("* 2"), (0, "expected a value, found `*`".to_string()));
+        assert_eq!(error("1.2.3"), (0, "bad number `1.2.3`".to_string()));
+        assert_eq!(error("dup + 1"), (0, "can't name a variable `dup`".to_string()));
+        assert_eq!(error("int(1 2)"), (6, "expected `)`, found `2`".to_string()));
+        let deep = "(".repeat(100_000);
+        assert_eq!(error(&deep), (MAX_NESTING, format!("expression nested more than {} deep", MAX_NESTING)));
+        assert_eq!(error(&"- ".repeat(100_000)).0, 2 * MAX_NESTING);
+    }
+}
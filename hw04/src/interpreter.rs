@@ -0,0 +1,167 @@
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+use check::{self, Checker, Effect, Ty};
+use ops::Operator;
+use parser::{self, evaluate_line, is_name, syntax, ScriptError, Session, Token};
+use rpn::{self, Elt, Stack};
+
+/// A calculator for other programs to embed. It evaluates source text as
+/// the command-line calculator does, and the program can add operators of
+/// its own that run Rust code:
+///
+/// ```
+/// use hw04::{Elt, Error, Interpreter};
+///
+/// let mut interp = Interpreter::new();
+/// interp.register("double", |stack| {
+///     match stack.pop()? {
+///         Elt::Int(n) => stack.push(Elt::Int(n * 2)),
+///         _ => Err(Error::Type(Default::default())),
+///     }
+/// }).unwrap();
+/// assert_eq!(interp.eval_str("20 1 + double").unwrap(), vec![Elt::Int(42)]);
+/// ```
+pub struct Interpreter {
+    pub(crate) session: Session,
+}
+
+impl Interpreter {
+    /// Creates an interpreter with an empty stack, no definitions and no
+    /// variables.
+    pub fn new() -> Interpreter {
+        Interpreter { session: Session::new() }
+    }
+
+    /// Evaluates `source` one line at a time, as a script, and returns the
+    /// stack, bottom first. Evaluation stops at `quit`, or at the first line
+    /// that fails, which is undone before its error is returned; the lines
+    /// before it keep their effects.
+    pub fn eval_str(&mut self, source: &str) -> rpn::Result<Vec<Elt>> {
+        for line in source.lines() {
+            match evaluate_line(&mut self.session, line) {
+                Ok(()) => {},
+                Err(rpn::Error::Quit) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.stack().to_vec())
+    }
+
+    /// Adds the operator `name`, which runs `f` on the stack, as
+    /// `register_op` does. When `f` fails with an underflow, type, range or
+    /// arity error, the error is reported as the operator's, with the stack
+    /// as it was before.
+    pub fn register<F>(&mut self, name: &str, f: F) -> rpn::Result<()>
+        where F: Fn(&mut Stack) -> rpn::Result<()> + 'static
+    {
+        self.register_op(Function { name: name.to_string(), f })
+    }
+
+    /// Adds an operator, replacing any operator of the same name, built-in
+    /// or not. Words defined already keep the operator they were defined
+    /// with. The name can't be that of a defined word, nor one that reads as
+    /// anything else, such as a number or a variable.
+    pub fn register_op<O: Operator + 'static>(&mut self, op: O) -> rpn::Result<()> {
+        let name = op.name().to_string();
+        let word = self.session.words.contains_key(&name);
+        if word || name.is_empty() || name.contains(char::is_whitespace) || !is_name(&name) {
+            return Err(syntax(Token { text: &name, column: 0 }, format!("can't define `{}`", name)));
+        }
+        self.session.ops.register(op);
+        Ok(())
+    }
+
+    /// The values on the stack, bottom first.
+    pub fn stack(&self) -> &[Elt] {
+        self.session.stack.values()
+    }
+
+    /// Seeds the random numbers that `#` produces, so that they are the
+    /// same on every run.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.session.stack.set_seed(seed);
+    }
+
+    /// Removes the operator `name`, built-in or not, returning it. Words
+    /// defined already keep using it.
+    pub fn unregister(&mut self, name: &str) -> Option<Rc<dyn Operator>> {
+        self.session.ops.remove(name)
+    }
+
+    /// Works out the stack effect of a line without running it, or `None`
+    /// if it can't be described. A line that would certainly fail gives
+    /// the error the checker found.
+    pub fn effect(&self, line: &str) -> rpn::Result<Option<Effect>> {
+        Checker::new(&self.session).effect(line)
+    }
+
+    /// Writes the stack, the definitions and the variables as text that
+    /// `load` reads back. Registered operators aren't saved.
+    pub fn save<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        self.session.save(output)
+    }
+
+    /// Replaces the stack, the definitions and the variables with those
+    /// saved by `save`, keeping the registered operators. If the text can't
+    /// be loaded, nothing changes.
+    pub fn load<R: BufRead>(&mut self, input: R) -> rpn::Result<()> {
+        self.session.load(input)
+    }
+
+    /// Runs a script without prompts, stopping at the first error or `quit`.
+    /// A `#!` first line is skipped. Afterwards, whatever is left on the
+    /// stack is written to `output`, one value per line, bottom first.
+    pub fn run_script<R: BufRead, W: Write>(&mut self, input: R, output: &mut W) -> Result<(), ScriptError> {
+        parser::run_script(&mut self.session, input, output)
+    }
+
+    /// Checks a script for stack underflows and type errors without running
+    /// it, returning the types it would leave on the stack if they are known,
+    /// or the first line that would certainly fail.
+    pub fn check_script<R: BufRead>(&self, input: R) -> Result<Option<Vec<Ty>>, ScriptError> {
+        check::check_script(&self.session, input)
+    }
+
+    /// Runs the interactive calculator on stdin and stdout until the end of
+    /// input (Ctrl-D) or `quit`. Only failing to read or write is an error,
+    /// returned as `Error::IO`. On a terminal, lines can be edited, recalled
+    /// from a history kept in `~/.hw04_history`, and completed with Tab.
+    pub fn read_eval_print_loop(&mut self) -> rpn::Result<()> {
+        parser::read_eval_print_loop(&mut self.session)
+    }
+
+    /// Runs the interactive calculator like `read_eval_print_loop`, reading
+    /// lines from `input` and writing prompts and results to `output`,
+    /// without line editing.
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: R, output: &mut W) -> rpn::Result<()> {
+        parser::repl(&mut self.session, input, output)
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+
+/// An operator made from a function, which takes what it needs from the
+/// stack itself.
+struct Function<F> {
+    name: String,
+    f: F,
+}
+
+impl<F> Operator for Function<F> where F: Fn(&mut Stack) -> rpn::Result<()> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn arity(&self) -> usize {
+        0
+    }
+
+    fn apply(&self, stack: &mut Stack) -> rpn::Result<()> {
+        (self.f)(stack)
+    }
+}
@@ -0,0 +1,28 @@
+//! An RPN calculator. `Interpreter` is the way in for programs that embed
+//! it, with the types below, and `serve` and `client` run it over a socket
+//! as the `hw04` command does.
+
+extern crate rand;
+
+mod bigint;
+mod bytecode;
+mod check;
+mod debugger;
+mod editor;
+mod infix;
+mod interpreter;
+mod ops;
+mod parser;
+mod rational;
+mod rpn;
+mod server;
+
+pub use bigint::BigInt;
+pub use bytecode::Word;
+pub use check::{Effect, Ty};
+pub use interpreter::Interpreter;
+pub use ops::Operator;
+pub use parser::ScriptError;
+pub use rational::Rational;
+pub use rpn::{Arith, Elt, Error, Fault, Op, Result, Snapshot, Stack, SyntaxError};
+pub use server::{client, serve};
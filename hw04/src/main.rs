@@ -1,12 +1,227 @@
-extern crate rand;
+extern crate hw04;
 
-pub mod parser;
-pub mod rpn;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, IsTerminal};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process;
 
-use parser::read_eval_print_loop;
+use hw04::{client, serve, Interpreter, Ty};
 
+/// Prints an error and exits with status 1.
+fn fail<E: std::fmt::Display>(err: E) -> ! {
+    eprintln!("Error: {}", err);
+    process::exit(1);
+}
+
+/// Reports a script that passed `--check`, with the types it would leave on
+/// the stack if they are known.
+fn print_check(types: Option<Vec<Ty>>) {
+    match types {
+        Some(types) => {
+            let types: Vec<String> = types.iter().map(|ty| ty.to_string()).collect();
+            println!("ok: leaves [{}]", types.join(" "));
+        },
+        None => println!("ok"),
+    }
+}
+
+/// Starts a server on a TCP address or, with `unix`, a Unix socket, serving
+/// interpreters made by `new_interpreter`. It runs until killed. Anyone who can
+/// connect can run anything, so TCP addresses must be loopback ones.
+fn start_server<F>(addr: &str, unix: bool, shared: bool, new_interpreter: F)
+    where F: Fn() -> Interpreter + Send + Sync + 'static
+{
+    if unix {
+        start_unix_server(addr, shared, new_interpreter);
+        return;
+    }
+    let addrs: Vec<_> = addr.to_socket_addrs().unwrap_or_else(|err| fail(format!("{}: {}", addr, err))).collect();
+    if addrs.iter().any(|a| !a.ip().is_loopback()) {
+        fail(format!("`--serve` only listens on loopback addresses, such as 127.0.0.1, not {}", addr));
+    }
+    let listener = TcpListener::bind(&addrs[..]).unwrap_or_else(|err| fail(format!("{}: {}", addr, err)));
+    match listener.local_addr() {
+        Ok(local) => eprintln!("listening on {}", local),
+        Err(err) => fail(err),
+    }
+    serve(listener.incoming(), shared, new_interpreter);
+}
+
+#[cfg(unix)]
+fn start_unix_server<F>(path: &str, shared: bool, new_interpreter: F)
+    where F: Fn() -> Interpreter + Send + Sync + 'static
+{
+    let listener = UnixListener::bind(path).unwrap_or_else(|err| fail(format!("{}: {}", path, err)));
+    eprintln!("listening on {}", path);
+    serve(listener.incoming(), shared, new_interpreter);
+}
+
+#[cfg(not(unix))]
+fn start_unix_server<F>(_path: &str, _shared: bool, _new_interpreter: F) {
+    fail("Unix sockets aren't supported here");
+}
+
+/// Connects to a server on a TCP address or, with `unix`, a Unix socket, and
+/// sends it the lines from stdin, printing its replies.
+fn start_client(addr: &str, unix: bool) {
+    let res = if unix {
+        connect_unix(addr)
+    } else {
+        TcpStream::connect(addr).and_then(|stream| client(&stream, io::stdin().lock(), &mut io::stdout()))
+    };
+    res.unwrap_or_else(|err| fail(format!("{}: {}", addr, err)));
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> io::Result<()> {
+    UnixStream::connect(path).and_then(|stream| client(&stream, io::stdin().lock(), &mut io::stdout()))
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &str) -> io::Result<()> {
+    fail("Unix sockets aren't supported here");
+}
+
+/// Runs `hw04 script.rpn`, or a script piped into stdin, without prompts,
+/// printing the final stack. With no script and a terminal on stdin, starts
+/// the interactive calculator instead. `--seed N` makes `#` produce the same
+/// numbers on every run, and `--session FILE` restores the session from FILE
+/// if it exists and saves it back there on a clean exit. `--check` checks the
+/// script for stack underflows and type errors instead of running it.
+///
+/// `--serve ADDR` serves the calculator over TCP instead, on a loopback
+/// address only, and `--unix PATH` over a Unix socket, one line per request
+/// and per reply. Each connection has a session of its own, starting from
+/// `--session` and `--seed` if they are given, unless `--shared` makes them
+/// all share one, which is never saved. `hw04 client ADDR` (or `hw04 client
+/// --unix PATH`) sends a server the lines from stdin and prints its replies.
+///
+/// Exits with status 0 when the input ends (Ctrl-D) or on `quit`, and with
+/// status 1 when a script fails or input can't be read.
 fn main() {
-    if let Err(err) = read_eval_print_loop() {
-        println!("Error: {:?}", err);
+    let mut seed = None;
+    let mut session_path = None;
+    let mut path = None;
+    let mut check = false;
+    let mut serve_addr = None;
+    let mut unix = false;
+    let mut shared = false;
+    let mut args = env::args().skip(1).peekable();
+    let client_mode = args.peek().map(|arg| arg.as_str()) == Some("client");
+    if client_mode {
+        args.next();
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => {
+                match args.next().and_then(|seed| seed.parse().ok()) {
+                    Some(n) => seed = Some(n),
+                    None => fail("`--seed` needs a non-negative integer"),
+                }
+            },
+            "--session" => {
+                match args.next() {
+                    Some(file) => session_path = Some(file),
+                    None => fail("`--session` needs a file name"),
+                }
+            },
+            "--check" => check = true,
+            "--serve" | "--unix" => {
+                unix = arg == "--unix";
+                match args.next() {
+                    Some(addr) => serve_addr = Some(addr),
+                    None => fail(format!("`{}` needs an address", arg)),
+                }
+            },
+            "--shared" => shared = true,
+            _ => path = Some(arg),
+        }
+    }
+
+    if client_mode {
+        match serve_addr.or(path) {
+            Some(addr) => start_client(&addr, unix),
+            None => fail("`client` needs an address"),
+        }
+        return;
+    }
+
+    if let Some(addr) = serve_addr {
+        // Interpreters are made on the threads that run them, from the
+        // saved text.
+        let saved = match session_path {
+            Some(ref file) => {
+                match fs::read_to_string(file) {
+                    Ok(text) => {
+                        Interpreter::new().load(text.as_bytes()).unwrap_or_else(|err| fail(format!("{}: {}", file, err)));
+                        Some(text)
+                    },
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+                    Err(err) => fail(format!("{}: {}", file, err)),
+                }
+            },
+            None => None,
+        };
+        start_server(&addr, unix, shared, move || {
+            let mut interp = Interpreter::new();
+            if let Some(ref text) = saved {
+                interp.load(text.as_bytes()).expect("loaded once already");
+            }
+            if let Some(seed) = seed {
+                interp.set_seed(seed);
+            }
+            interp
+        });
+        return;
+    }
+
+    let mut interp = Interpreter::new();
+    if let Some(ref file) = session_path {
+        match File::open(file) {
+            Ok(f) => interp.load(BufReader::new(f)).unwrap_or_else(|err| fail(format!("{}: {}", file, err))),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => fail(format!("{}: {}", file, err)),
+        }
+    }
+    if let Some(seed) = seed {
+        interp.set_seed(seed);
+    }
+
+    if check {
+        let res = match path {
+            Some(path) => {
+                match File::open(&path) {
+                    Ok(file) => interp.check_script(BufReader::new(file)),
+                    Err(err) => fail(format!("{}: {}", path, err)),
+                }
+            },
+            None => interp.check_script(io::stdin().lock()),
+        };
+        print_check(res.unwrap_or_else(|err| fail(err)));
+        return;
+    }
+
+    match path {
+        Some(path) => {
+            match File::open(&path) {
+                Ok(file) => {
+                    interp.run_script(BufReader::new(file), &mut io::stdout()).unwrap_or_else(|err| fail(err))
+                },
+                Err(err) => fail(format!("{}: {}", path, err)),
+            }
+        },
+        None if !io::stdin().is_terminal() => {
+            interp.run_script(io::stdin().lock(), &mut io::stdout()).unwrap_or_else(|err| fail(err))
+        },
+        None => interp.read_eval_print_loop().unwrap_or_else(|err| fail(err)),
+    }
+
+    if let Some(file) = session_path {
+        if let Err(err) = File::create(&file).and_then(|mut f| interp.save(&mut f)) {
+            fail(format!("{}: {}", file, err));
+        }
     }
 }
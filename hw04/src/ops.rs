@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use check::Effect;
+use rpn::{self, Op, Stack};
+
+/// An operator: a token that runs Rust code on the stack. The built-in
+/// operators are `Op`s; a program embedding the calculator can add its own.
+pub trait Operator {
+    /// The token that runs the operator.
+    fn name(&self) -> &str;
+
+    /// How many values the operator takes. It is never applied to a stack
+    /// holding fewer.
+    fn arity(&self) -> usize;
+
+    /// The types the operator takes and leaves, for the checker, or `None`
+    /// if they can't be described, in which case the checker only counts on
+    /// `arity`.
+    fn effect(&self) -> Option<Effect> {
+        None
+    }
+
+    /// Applies the operator to the stack. Underflows, type errors and the
+    /// like are blamed on the operator, with the stack as it was before, so
+    /// their faults may be left empty.
+    fn apply(&self, stack: &mut Stack) -> rpn::Result<()>;
+
+    /// The built-in operator this is, if it is one. Built-ins are compiled
+    /// and checked by rules of their own.
+    fn builtin(&self) -> Option<Op> {
+        None
+    }
+}
+
+/// Built-in operators. Those that run quotations need the whole session,
+/// so they are only run from compiled code, never through `apply`.
+impl Operator for Op {
+    fn name(&self) -> &str {
+        self.token()
+    }
+
+    fn arity(&self) -> usize {
+        Op::arity(*self)
+    }
+
+    fn apply(&self, stack: &mut Stack) -> rpn::Result<()> {
+        stack.eval(*self)
+    }
+
+    fn builtin(&self) -> Option<Op> {
+        Some(*self)
+    }
+}
+
+impl fmt::Debug for dyn Operator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Operator({:?})", self.name())
+    }
+}
+
+/// Applies an operator, first checking that the stack holds enough values
+/// for it, and blaming any failure on it.
+pub fn apply(op: &dyn Operator, stack: &mut Stack) -> rpn::Result<()> {
+    let fault = stack.fault(op.name());
+    if stack.values().len() < op.arity() {
+        return Err(rpn::Error::Underflow(fault));
+    }
+    op.apply(stack).map_err(|err| err.at(fault))
+}
+
+/// The operators a session knows, by token.
+#[derive(Clone)]
+pub struct Registry {
+    ops: HashMap<String, Rc<dyn Operator>>,
+}
+
+impl Registry {
+    /// Creates a registry holding the built-in operators.
+    pub fn new() -> Registry {
+        let mut ops: HashMap<String, Rc<dyn Operator>> = HashMap::new();
+        for token in Op::tokens() {
+            let op = Op::from_token(token).expect("every token has an op");
+            ops.insert(token.to_string(), Rc::new(op));
+        }
+        Registry { ops }
+    }
+
+    /// Finds the operator written as `token`, if any.
+    pub fn get(&self, token: &str) -> Option<&Rc<dyn Operator>> {
+        self.ops.get(token)
+    }
+
+    /// Adds an operator under its name, returning the operator it replaces,
+    /// if any. Built-in operators can be replaced too.
+    pub fn register<O: Operator + 'static>(&mut self, op: O) -> Option<Rc<dyn Operator>> {
+        self.ops.insert(op.name().to_string(), Rc::new(op))
+    }
+
+    /// Removes the operator written as `token`.
+    pub fn remove(&mut self, token: &str) -> Option<Rc<dyn Operator>> {
+        self.ops.remove(token)
+    }
+
+    /// The tokens of every operator, in no particular order.
+    pub fn tokens(&self) -> impl Iterator<Item = &str> {
+        self.ops.keys().map(|t| t.as_str())
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use check::{Effect, Ty};
+    use ops::{apply, Operator, Registry};
+    use rpn::{self, Elt, Error, Fault, Op, Stack};
+
+    struct Square;
+
+    impl Operator for Square {
+        fn name(&self) -> &str {
+            "sq"
+        }
+
+        fn arity(&self) -> usize {
+            1
+        }
+
+        fn effect(&self) -> Option<Effect> {
+            Some(Effect::new(&[Ty::Int], &[Ty::Int]))
+        }
+
+        fn apply(&self, stack: &mut Stack) -> rpn::Result<()> {
+            stack.eval(Op::Dup)?;
+            stack.eval(Op::Mul)
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut ops = Registry::new();
+        assert_eq!(ops.get("<->").and_then(|op| op.builtin()), Some(Op::Swap));
+        assert_eq!(ops.get("pick").map(|op| op.arity()), Some(1));
+        assert!(ops.get("sq").is_none());
+        assert!(ops.register(Square).is_none());
+        assert_eq!(ops.get("sq").map(|op| op.name()), Some("sq"));
+        assert!(ops.get("sq").and_then(|op| op.builtin()).is_none());
+        assert_eq!(ops.tokens().count(), Op::tokens().count() + 1);
+        assert!(ops.remove("+").is_some());
+        assert!(ops.get("+").is_none());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut stack = Stack::new();
+        stack.push(Elt::Int(7)).unwrap();
+        apply(&Square, &mut stack).unwrap();
+        assert_eq!(stack.values(), &[Elt::Int(49)]);
+        apply(&Op::Drop, &mut stack).unwrap();
+        if let Err(Error::Underflow(fault)) = apply(&Square, &mut stack) {
+            assert_eq!(fault, Fault { op: "sq".to_string(), stack: vec![] });
+        } else {
+            panic!();
+        }
+        stack.push(Elt::Bool(true)).unwrap();
+        if let Err(Error::Type(fault)) = apply(&Square, &mut stack) {
+            assert_eq!(fault, Fault { op: "sq".to_string(), stack: vec![Elt::Bool(true)] });
+        } else {
+            panic!();
+        }
+    }
+}
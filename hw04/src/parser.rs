@@ -1,142 +1,1112 @@
-use std::io::{self, Write};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::rc::Rc;
 use std::str::FromStr;
+use std::vec;
 
-use rpn::{self, Stack};
+use bigint::BigInt;
+use bytecode::{self, Word};
+use check::Checker;
+use debugger::Debugger;
+use editor::{self, Editor};
+use infix;
+use ops::{Operator, Registry};
+use rational::Rational;
+use rpn::{self, Observer, Stack, Undo, Vars};
 
-/// Start a read-eval-print loop, which runs until an error or `quit`.
-pub fn read_eval_print_loop() -> rpn::Result<()> {
-    // Create a stack to work on.
-    let mut stack = Stack::new();
+/// How many stack states the REPL keeps for `undo`, unless told otherwise.
+const UNDO_DEPTH: usize = 100;
 
-    loop {
-        // Print a user input prompt.
-        print!("> ");
-        try!(io::stdout().flush().map_err(rpn::Error::IO));
+/// How deeply blocks, quotations and lists may nest on a line. Parsing,
+/// checking and running them all recurse, so this keeps a line from
+/// overflowing the stack.
+pub const MAX_NESTING: usize = 100;
+
+/// A calculator session: the stack plus the operators it knows and the
+/// words and variables defined on it.
+#[derive(Default)]
+pub struct Session {
+    pub stack: Stack,
+    pub ops: Registry,
+    /// User-defined words, by name.
+    pub words: HashMap<String, Rc<Word>>,
+    pub vars: Vars,
+}
+
+impl Session {
+    /// Creates a new Session with an empty stack, the built-in operators, no
+    /// definitions and no variables.
+    pub fn new() -> Session {
+        Session { stack: Stack::new(), ops: Registry::new(), words: HashMap::new(), vars: Vars::new() }
+    }
+
+    /// Writes the overflow mode, the stack (bottom first), the variables and
+    /// the definitions as lines of text that `Session::load` reads back.
+    pub fn save<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        writeln!(output, "# hw04 session")?;
+        writeln!(output, "mode {}", self.stack.arith())?;
+        for elt in self.stack.values() {
+            writeln!(output, "stack {}", typed(elt))?;
+        }
+        for (name, elt) in self.vars.list() {
+            writeln!(output, "var {} {}", name, typed(elt))?;
+        }
+        let mut names: Vec<&String> = self.words.keys().collect();
+        names.sort();
+        for name in names {
+            writeln!(output, "{}", Node::Define(name.clone(), self.words[name].body.clone()))?;
+        }
+        Ok(())
+    }
+
+    /// Reads a session written by `Session::save` into this one, replacing
+    /// its stack, mode, definitions and variables, but keeping its operators
+    /// and the state of `#`. Blank lines and lines starting with `#` are
+    /// skipped; anything else that isn't understood is an
+    /// `rpn::Error::Corrupt`, and leaves the session as it was.
+    pub fn load<R: BufRead>(&mut self, input: R) -> rpn::Result<()> {
+        let lines = input.lines().collect::<io::Result<Vec<String>>>()?;
+        let snapshot = self.stack.snapshot();
+        let words = mem::take(&mut self.words);
+        let vars = mem::take(&mut self.vars);
+
+        let res = self.read(&lines);
+        if res.is_err() {
+            self.stack.restore(snapshot);
+            self.words = words;
+            self.vars = vars;
+        }
+        res
+    }
+
+    /// Reads the lines of a saved session into this one, which has no
+    /// definitions or variables yet.
+    fn read(&mut self, lines: &[String]) -> rpn::Result<()> {
+        self.stack.eval(rpn::Op::Clear)?;
+        self.stack.set_arith(rpn::Arith::default());
+
+        // Make every saved word known up front, so that definitions may use
+        // each other in any order.
+        for line in lines {
+            let mut tokens = line.split_whitespace();
+            if let (Some(":"), Some(name)) = (tokens.next(), tokens.next()) {
+                self.words.insert(name.to_string(), Rc::new(Word::new(Rc::new(Vec::new()))));
+            }
+        }
+
+        for (i, line) in lines.iter().enumerate() {
+            let corrupt = |message: String| rpn::Error::Corrupt(i + 1, message);
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            match tokens[..] {
+                [] => {},
+                [first, ..] if first.starts_with('#') => {},
+                [":", ..] => {
+                    let mut nodes = parse_line(&self.ops, &self.words, line).map_err(|e| corrupt(e.to_string()))?;
+                    match nodes.pop() {
+                        Some(Node::Define(name, body)) if nodes.is_empty() => {
+                            self.words.insert(name, Rc::new(Word::new(body)));
+                        },
+                        _ => return Err(corrupt("expected a single definition".to_string())),
+                    }
+                },
+                ["mode", name] => {
+                    let arith = name.parse().map_err(|_| corrupt(format!("unknown mode `{}`", name)))?;
+                    self.stack.set_arith(arith);
+                },
+                ["stack", kind, _, ..] => {
+                    let value = rest_of(line, 2);
+                    let elt = untyped(&self.ops, &self.words, kind, value)
+                        .ok_or_else(|| corrupt(format!("bad {} `{}`", kind, value)))?;
+                    self.stack.push(elt)?;
+                },
+                ["var", name, kind, _, ..] => {
+                    let value = rest_of(line, 3);
+                    let elt = untyped(&self.ops, &self.words, kind, value)
+                        .ok_or_else(|| corrupt(format!("bad {} `{}`", kind, value)))?;
+                    let name = var_name(Token { text: name, column: 0 }, name).map_err(|e| corrupt(e.to_string()))?;
+                    self.vars.store(&name, elt);
+                },
+                _ => return Err(corrupt(format!("can't understand `{}`", line.trim()))),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The rest of `line` after its first `n` whitespace-separated fields, for
+/// values such as strings that may hold spaces themselves.
+fn rest_of(line: &str, n: usize) -> &str {
+    let mut rest = line.trim();
+    for _ in 0..n {
+        rest = rest[rest.find(char::is_whitespace).unwrap_or(rest.len())..].trim_start();
+    }
+    rest
+}
+
+/// Writes a value as its type and text, e.g. `rat 1/2`, so that floats and
+/// integers that print alike are told apart when they are read back.
+fn typed(elt: &rpn::Elt) -> String {
+    match *elt {
+        rpn::Elt::Int(n) => format!("int {}", n),
+        rpn::Elt::Bool(b) => format!("bool {}", b),
+        rpn::Elt::Float(x) => format!("float {:?}", x),
+        rpn::Elt::Rational(r) => format!("rat {}", r),
+        rpn::Elt::Big(ref n) => format!("big {}", n),
+        rpn::Elt::Str(_) => format!("str {}", elt),
+        rpn::Elt::List(_) => format!("list {}", elt),
+        rpn::Elt::Quote(_) => format!("quote {}", elt),
+    }
+}
+
+/// Reads back a value written by `typed`. Quotations may use `ops` and call
+/// `words`.
+fn untyped(ops: &Registry, words: &HashMap<String, Rc<Word>>, kind: &str, value: &str) -> Option<rpn::Elt> {
+    match kind {
+        "int" => i32::from_str(value).ok().map(rpn::Elt::Int),
+        "bool" => bool::from_str(value).ok().map(rpn::Elt::Bool),
+        "float" => f64::from_str(value).ok().map(rpn::Elt::Float),
+        "rat" => Rational::from_str(value).ok().map(rpn::Elt::Rational),
+        "big" => BigInt::from_str(value).ok().map(rpn::Elt::from_big),
+        "str" | "list" | "quote" => {
+            let elt = match parse_line(ops, words, value).ok()?[..] {
+                [ref node] => bytecode::constant(node)?,
+                _ => return None,
+            };
+            match (kind, &elt) {
+                ("str", &rpn::Elt::Str(_)) | ("list", &rpn::Elt::List(_)) | ("quote", &rpn::Elt::Quote(_)) => Some(elt),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Start a read-eval-print loop on stdin and stdout, which runs until the
+/// end of input (Ctrl-D) or `quit`. Only failing to read or write is an
+/// error, returned as `rpn::Error::IO`.
+///
+/// On a terminal, lines can be edited, recalled from a history kept in
+/// `~/.hw04_history`, and completed with Tab.
+pub fn read_eval_print_loop(session: &mut Session) -> rpn::Result<()> {
+    let mut editor = match editor::default_history_path() {
+        Some(path) => Editor::with_history_file(path),
+        None => Editor::new(),
+    };
+    run_loop(session, &mut io::stdout(), |prompt, words, output| {
+        output.flush()?;
+        editor.read_line(prompt, words)
+    })
+}
 
-        // TODO: Read from stdin into a String, and evaluate_line the result.
-        // * An io::Error should be converted into a rpn::Error::IO
+/// The tokens Tab can complete: the session's operators, keywords, REPL
+/// commands, and the session's words and variables.
+fn completions(session: &Session) -> Vec<String> {
+    let mut words: Vec<String> = session.ops.tokens()
+        .chain(KEYWORDS.iter().cloned())
+        .chain(COMMANDS.iter().cloned())
+        .map(|t| t.to_string())
+        .collect();
+    words.extend(session.words.keys().cloned());
+    for (name, _) in session.vars.list() {
+        words.push(format!("@{}", name));
+        words.push(format!("!{}", name));
+    }
+    words
+}
+
+/// What the REPL shows after each line.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Show {
+    /// The value on top of the stack.
+    Top,
+    /// The whole stack, as `.s` prints it.
+    Stack,
+    /// Nothing at all.
+    Nothing,
+}
+
+impl FromStr for Show {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Show, ()> {
+        match s {
+            "top" => Ok(Show::Top),
+            "stack" => Ok(Show::Stack),
+            "none" => Ok(Show::Nothing),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Runs a read-eval-print loop over any input and output.
+///
+/// Besides RPN lines, the loop understands a few commands of its own: `.s`
+/// prints the whole stack, `vars` prints every variable,
+/// `display top|stack|none` changes what is shown after each line,
+/// `save file` and `load file` write the session to a file and read it back,
+/// and `undo` and `redo` step back and forth through the states of the stack.
+/// `undo-depth n` sets how many states are kept. `disasm line` shows what a
+/// line compiles to without running it, and `see word` shows a word's code.
+/// `check line` checks a line against the stack without running it, and
+/// shows its stack effect.
+///
+/// `trace on|off` turns on and off printing each token with the stack
+/// before and after it. `break word` makes the debugger stop whenever `word`
+/// is called, `unbreak word` undoes it, and `break` lists those words.
+/// `step line` runs a line stopping before each token.
+///
+/// Any line written `=expr` is an infix expression such as `=(3 + 4) * -x`,
+/// and after `infix on` every line is one, until `infix off`. `show-rpn expr`
+/// shows the RPN an expression translates to.
+pub fn repl<R, W>(session: &mut Session, mut input: R, output: &mut W) -> rpn::Result<()>
+    where R: BufRead, W: Write
+{
+    run_loop(session, output, |prompt, _, output| {
+        write!(output, "{}", prompt)?;
+        output.flush()?;
         let mut line = String::new();
-        try!(io::stdin().read_line(&mut line).map_err(rpn::Error::IO));
-        match evaluate_line(&mut stack, &line) {
-            Ok(_) => {
-                match stack.pop() {
-                    Ok(rpn::Elt::Int(n)) => println!("  {}", n),
-                    Ok(rpn::Elt::Bool(b)) => println!("  {}", b),
-                    Err(_) => println!("Error"),
+        Ok(if input.read_line(&mut line)? == 0 { None } else { Some(line) })
+    })
+}
+
+/// The REPL itself, which gets each line by calling `read_line` with the
+/// prompt to show and the words Tab can complete. `read_line` returns `None`
+/// at the end of input.
+fn run_loop<W, F>(session: &mut Session, output: &mut W, mut read_line: F) -> rpn::Result<()>
+    where W: Write, F: FnMut(&str, &[String], &mut W) -> io::Result<Option<String>>
+{
+    let mut repl = Repl {
+        show: Show::Top,
+        undo: Undo::new(UNDO_DEPTH),
+        trace: false,
+        breakpoints: BTreeSet::new(),
+        infix: false,
+    };
+
+    loop {
+        // Prompt for input, naming the overflow mode unless it is the
+        // default, and infix mode.
+        let prompt = match (session.stack.arith(), repl.infix) {
+            (rpn::Arith::Bignum, false) => "> ".to_string(),
+            (rpn::Arith::Bignum, true) => "infix> ".to_string(),
+            (arith, false) => format!("{}> ", arith),
+            (arith, true) => format!("{} infix> ", arith),
+        };
+        let line = match read_line(&prompt, &completions(session), output)? {
+            Some(line) => line,
+            None => {
+                // End of input: finish the prompt's line before leaving.
+                writeln!(output)?;
+                return Ok(());
+            },
+        };
+        let res = match command(session, &mut repl, &line, output) {
+            Some(res) => res,
+            None => {
+                // `step line` stops before each token of the line.
+                let stepping = tokenize(&line).first().map(|t| t.text) == Some("step");
+                let source = if stepping {
+                    &line[line.find("step").expect("the line starts with `step`") + 4..]
+                } else {
+                    &line[..]
+                };
+                let before = session.stack.snapshot();
+                let res = {
+                    let mut debugger = Debugger::new(repl.trace, &repl.breakpoints, stepping, output,
+                                                     &mut read_line);
+                    if repl.infix && infix_expr(source).is_none() && !source.trim().is_empty() {
+                        infix::translate(source).and_then(|rpn| {
+                            evaluate_line_with(session, &rpn.text(), &mut debugger).map_err(|e| rpn.locate(e))
+                        })
+                    } else {
+                        evaluate_line_with(session, source, &mut debugger)
+                    }
+                };
+                res.map_err(|e| within(e, &line, source)).and_then(|_| {
+                    repl.undo.record(before, &session.stack);
+                    show_result(session, repl.show, output)
+                })
+            },
+        };
+        match res {
+            Ok(_) => {},
+            Err(rpn::Error::Quit) => return Ok(()),
+            Err(e) => {
+                // Point at the offending token, just below where it was typed.
+                if let rpn::Error::Syntax(ref err) | rpn::Error::Check(ref err) = e {
+                    writeln!(output, "{}^", " ".repeat(prompt.chars().count() + err.column))?;
                 }
+                writeln!(output, "  {}", e)?;
             },
-            Err(e) => println!("  {:?}", e),
-        }
-        
-    }
-}
-
-fn evaluate_line(stack: &mut Stack, buf: &String) -> rpn::Result<()> {
-    // Create an iterator over the tokens.
-    let tokens = buf.trim().split_whitespace();
-
-    for op in tokens {
-        let res: rpn::Result<()> = match op {
-            "+" => stack.eval(rpn::Op::Add),
-            "~" => stack.eval(rpn::Op::Neg),
-            "<->" => stack.eval(rpn::Op::Swap),
-            "=" => stack.eval(rpn::Op::Eq),
-            "#" => stack.eval(rpn::Op::Rand),
-            //"true" | "false" => stack.push(Elt::Bool(bool::from_str(op).unwrap())),
-            "true" => stack.push(rpn::Elt::Bool(true)),
-            "false" => stack.push(rpn::Elt::Bool(false)),
-            "quit" => return stack.eval(rpn::Op::Quit),
-            _ => {
-                match i32::from_str(op) {
-                    Ok(n) => stack.push(rpn::Elt::Int(n)),
-                    Err(_) => return Err(rpn::Error::Syntax),
+        }
+    }
+}
+
+/// The REPL's own state, apart from the session.
+struct Repl {
+    show: Show,
+    undo: Undo,
+    /// Whether to trace each line as it runs.
+    trace: bool,
+    /// The words the debugger stops at.
+    breakpoints: BTreeSet<String>,
+    /// Whether lines are infix expressions rather than RPN.
+    infix: bool,
+}
+
+/// Shows what `show` asks for after a line has run.
+fn show_result<W: Write>(session: &Session, show: Show, output: &mut W) -> rpn::Result<()> {
+    match show {
+        Show::Top => {
+            match session.stack.peek() {
+                Ok(elt) => writeln!(output, "  {}", elt).map_err(rpn::Error::IO),
+                Err(_) => Ok(()),
+            }
+        },
+        Show::Stack => print_stack(&session.stack, output),
+        Show::Nothing => Ok(()),
+    }
+}
+
+/// The commands understood by the REPL, but not in scripts.
+const COMMANDS: &[&str] = &[".s", "vars", "display", "save", "load", "undo", "redo", "undo-depth",
+                            "disasm", "see", "check", "trace", "break", "unbreak", "step", "infix",
+                            "show-rpn"];
+
+/// Runs the REPL command on `line`, if it is one.
+fn command<W: Write>(session: &mut Session, repl: &mut Repl, line: &str, output: &mut W)
+    -> Option<rpn::Result<()>>
+{
+    let tokens = tokenize(line);
+    match tokens.first().map(|t| t.text) {
+        Some(".s") if tokens.len() == 1 => {
+            let (back, forward) = repl.undo.position();
+            Some(print_stack(&session.stack, output).and_then(|_| {
+                writeln!(output, "  (undo {}/{}, redo {})", back, repl.undo.depth(), forward)
+                    .map_err(rpn::Error::IO)
+            }))
+        },
+        Some("disasm") => {
+            let source = &line[line.find("disasm").expect("the line starts with `disasm`") + 6..];
+            Some(parse_line(&session.ops, &session.words, source).and_then(|nodes| {
+                print_listing(&bytecode::compile(&nodes), output)
+            }).map_err(|e| within(e, line, source)))
+        },
+        Some("check") => {
+            let source = &line[line.find("check").expect("the line starts with `check`") + 5..];
+            let mut checker = Checker::new(session);
+            Some(checker.check_line(source).and_then(|_| {
+                match Checker::new(session).effect(source)? {
+                    Some(effect) => writeln!(output, "  {}", effect)?,
+                    None => writeln!(output, "  ok")?,
                 }
+                Ok(())
+            }).map_err(|e| within(e, line, source)))
+        },
+        Some("see") if tokens.len() == 2 => {
+            Some(match session.words.get(tokens[1].text) {
+                Some(word) => print_listing(&word.code, output),
+                None => Err(syntax(tokens[1], format!("unknown word `{}`", tokens[1].text))),
+            })
+        },
+        Some("undo") if tokens.len() == 1 => {
+            Some(if repl.undo.undo(&mut session.stack) {
+                show_result(session, repl.show, output)
+            } else {
+                writeln!(output, "  nothing to undo").map_err(rpn::Error::IO)
+            })
+        },
+        Some("redo") if tokens.len() == 1 => {
+            Some(if repl.undo.redo(&mut session.stack) {
+                show_result(session, repl.show, output)
+            } else {
+                writeln!(output, "  nothing to redo").map_err(rpn::Error::IO)
+            })
+        },
+        Some("undo-depth") => {
+            Some(match tokens.get(1) {
+                Some(depth) if tokens.len() == 2 => {
+                    depth.text.parse()
+                        .map(|depth| repl.undo.set_depth(depth))
+                        .map_err(|_| syntax(*depth, format!("bad undo depth `{}`", depth.text)))
+                },
+                _ => Err(syntax(tokens[0], "expected `undo-depth n`".to_string())),
+            })
+        },
+        Some("trace") => {
+            Some(match tokens.get(1).map(|t| t.text) {
+                Some(mode) if tokens.len() == 2 && (mode == "on" || mode == "off") => {
+                    repl.trace = mode == "on";
+                    Ok(())
+                },
+                _ => Err(syntax(tokens[0], "expected `trace on|off`".to_string())),
+            })
+        },
+        Some("infix") => {
+            Some(match tokens.get(1).map(|t| t.text) {
+                Some(mode) if tokens.len() == 2 && (mode == "on" || mode == "off") => {
+                    repl.infix = mode == "on";
+                    Ok(())
+                },
+                _ => Err(syntax(tokens[0], "expected `infix on|off`".to_string())),
+            })
+        },
+        Some("show-rpn") => {
+            let source = &line[line.find("show-rpn").expect("the line starts with `show-rpn`") + 8..];
+            let expr = infix_expr(source).unwrap_or(source);
+            Some(infix::to_rpn(expr)
+                 .and_then(|rpn| writeln!(output, "  {}", rpn.join(" ")).map_err(rpn::Error::IO))
+                 .map_err(|e| within(e, line, expr)))
+        },
+        Some("break") if tokens.len() == 1 => {
+            Some(repl.breakpoints.iter().try_for_each(|name| writeln!(output, "  {}", name)).map_err(rpn::Error::IO))
+        },
+        Some("break") if tokens.len() == 2 => {
+            Some(if session.words.contains_key(tokens[1].text) {
+                repl.breakpoints.insert(tokens[1].text.to_string());
+                Ok(())
+            } else {
+                Err(syntax(tokens[1], format!("unknown word `{}`", tokens[1].text)))
+            })
+        },
+        Some("unbreak") if tokens.len() == 2 => {
+            Some(if repl.breakpoints.remove(tokens[1].text) {
+                Ok(())
+            } else {
+                Err(syntax(tokens[1], format!("no breakpoint at `{}`", tokens[1].text)))
+            })
+        },
+        Some("vars") if tokens.len() == 1 => Some(print_vars(&session.vars, output)),
+        Some("save") if tokens.len() == 2 => {
+            Some(File::create(tokens[1].text).and_then(|mut file| session.save(&mut file)).map_err(rpn::Error::IO))
+        },
+        Some("load") if tokens.len() == 2 => {
+            Some(File::open(tokens[1].text)
+                 .map_err(rpn::Error::IO)
+                 .and_then(|file| {
+                     let before = session.stack.snapshot();
+                     session.load(BufReader::new(file))?;
+                     repl.undo.record(before, &session.stack);
+                     Ok(())
+                 }))
+        },
+        Some("display") => {
+            Some(match tokens.get(1) {
+                Some(mode) if tokens.len() == 2 => {
+                    mode.text.parse()
+                        .map(|mode| repl.show = mode)
+                        .map_err(|_| syntax(*mode, format!("unknown display mode `{}`", mode.text)))
+                },
+                _ => Err(syntax(tokens[0], "expected `display top|stack|none`".to_string())),
+            })
+        },
+        _ => None,
+    }
+}
+
+/// Moves the column of an error in `source` to where it is in `line`, which
+/// ends with `source`.
+pub fn within(e: rpn::Error, line: &str, source: &str) -> rpn::Error {
+    let shift = line[..line.len() - source.len()].chars().count();
+    match e {
+        rpn::Error::Syntax(mut err) => {
+            err.column += shift;
+            rpn::Error::Syntax(err)
+        },
+        rpn::Error::Check(mut err) => {
+            err.column += shift;
+            rpn::Error::Check(err)
+        },
+        e => e,
+    }
+}
+
+/// Prints the whole stack, top last, with each value's depth below the top.
+fn print_stack<W: Write>(stack: &Stack, output: &mut W) -> rpn::Result<()> {
+    let values = stack.values();
+    for (i, elt) in values.iter().enumerate() {
+        writeln!(output, "  {}: {}", values.len() - 1 - i, elt)?;
+    }
+    Ok(())
+}
+
+/// Prints a program's disassembly.
+fn print_listing<W: Write>(code: &[bytecode::Instr], output: &mut W) -> rpn::Result<()> {
+    for line in bytecode::disassemble(code).lines() {
+        writeln!(output, "  {}", line)?;
+    }
+    Ok(())
+}
+
+/// Prints each variable and its value, in order of name.
+fn print_vars<W: Write>(vars: &Vars, output: &mut W) -> rpn::Result<()> {
+    for (name, elt) in vars.list() {
+        writeln!(output, "  {} = {}", name, elt)?;
+    }
+    Ok(())
+}
+
+/// An error that stopped a script, and the line (counting from 1) where it
+/// happened.
+#[derive(Debug)]
+pub struct ScriptError {
+    pub line: usize,
+    pub error: rpn::Error,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.error)
+    }
+}
+
+/// Runs a script without prompts, stopping at the first error or `quit`. A
+/// `#!` first line is skipped. Afterwards, whatever is left on the stack is
+/// written to `output`, one value per line, bottom first.
+pub fn run_script<R, W>(session: &mut Session, input: R, output: &mut W) -> Result<(), ScriptError>
+    where R: BufRead, W: Write
+{
+    let mut count = 0;
+
+    for (i, line) in input.lines().enumerate() {
+        count = i + 1;
+        let fail = |error| ScriptError { line: count, error };
+        let line = line.map_err(|e| fail(rpn::Error::IO(e)))?;
+        if i == 0 && line.starts_with("#!") {
+            continue;
+        }
+        match evaluate_line(session, &line) {
+            Ok(()) => {},
+            Err(rpn::Error::Quit) => break,
+            Err(e) => return Err(fail(e)),
+        }
+    }
+
+    for elt in session.stack.values() {
+        writeln!(output, "{}", elt).map_err(|e| ScriptError { line: count, error: rpn::Error::IO(e) })?;
+    }
+    Ok(())
+}
+
+/// Evaluates a line as a unit: if any token fails, the stack, the
+/// definitions and the variables are put back the way they were before the
+/// line.
+pub fn evaluate_line(session: &mut Session, buf: &str) -> rpn::Result<()> {
+    evaluate_line_with(session, buf, &mut ())
+}
+
+/// Evaluates a line like `evaluate_line`, telling `observer` about each
+/// token as it runs.
+pub fn evaluate_line_with<O: Observer>(session: &mut Session, buf: &str, observer: &mut O) -> rpn::Result<()> {
+    if let Some(expr) = infix_expr(buf) {
+        let rpn = infix::translate(expr).map_err(|e| within(e, buf, expr))?;
+        return evaluate_line_with(session, &rpn.text(), observer).map_err(|e| within(rpn.locate(e), buf, expr));
+    }
+    let snapshot = session.stack.snapshot();
+    let words = session.words.clone();
+    let vars = session.vars.clone();
+
+    let res = parse_line(&session.ops, &session.words, buf)
+        .and_then(|nodes| bytecode::run(session, &bytecode::compile(&nodes), 0, observer));
+    if res.is_err() {
+        session.stack.restore(snapshot);
+        session.words = words;
+        session.vars = vars;
+    }
+    res
+}
+
+/// The infix expression on a line written `=expr`, if it is one. The
+/// expression must follow `=` directly, since `=` on its own compares the
+/// top two values.
+pub fn infix_expr(line: &str) -> Option<&str> {
+    let line = line.trim_start();
+    match line.chars().nth(1) {
+        Some(c) if line.starts_with('=') && !c.is_whitespace() => Some(&line[1..]),
+        _ => None,
+    }
+}
+
+/// A parsed piece of a line.
+#[derive(Debug)]
+pub enum Node {
+    /// A literal or user-defined word.
+    Word(String),
+    /// An operator, as found in the session's registry.
+    Op(Rc<dyn Operator>),
+    /// `: name body ;` defines a word.
+    Define(String, Rc<Vec<Node>>),
+    /// `mode name` switches how integer overflow is handled.
+    Mode(rpn::Arith),
+    /// `if a else b then` pops a boolean and runs `a` if it is true, or `b`
+    /// otherwise. The `else` part is optional.
+    If(Vec<Node>, Vec<Node>),
+    /// `begin body until` runs `body`, then pops a boolean, until it is true.
+    BeginUntil(Vec<Node>),
+    /// `limit start do body loop` pops `start` and `limit` and runs `body`
+    /// once for each index in `[start, limit)`.
+    DoLoop(Vec<Node>),
+    /// `i` pushes the index of the innermost enclosing `do` loop.
+    Index,
+    /// `!name` pops a value into a variable.
+    Store(String),
+    /// `@name` pushes the value of a variable.
+    Fetch(String),
+    /// `unset name` deletes a variable.
+    Unset(String),
+    /// `{ a b }` pushes a list of literal values, lists and quotations.
+    List(Vec<Node>),
+    /// `[ body ]` pushes `body` as a quotation, without running it.
+    Quote(Rc<Vec<Node>>),
+}
+
+impl fmt::Display for Node {
+    /// Writes the node back out as the tokens it was parsed from.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Node::Word(ref token) => f.write_str(token),
+            Node::Op(ref op) => f.write_str(op.name()),
+            Node::Define(ref name, ref body) => write!(f, ": {}{} ;", name, Block(body)),
+            Node::Mode(arith) => write!(f, "mode {}", arith),
+            Node::If(ref then, ref otherwise) if otherwise.is_empty() => write!(f, "if{} then", Block(then)),
+            Node::If(ref then, ref otherwise) => {
+                write!(f, "if{} else{} then", Block(then), Block(otherwise))
             },
-        };
-        res.unwrap();
+            Node::BeginUntil(ref body) => write!(f, "begin{} until", Block(body)),
+            Node::DoLoop(ref body) => write!(f, "do{} loop", Block(body)),
+            Node::Index => f.write_str("i"),
+            Node::Store(ref name) => write!(f, "!{}", name),
+            Node::Fetch(ref name) => write!(f, "@{}", name),
+            Node::Unset(ref name) => write!(f, "unset {}", name),
+            Node::List(ref items) => write!(f, "{{{} }}", Block(items)),
+            Node::Quote(ref body) => write!(f, "[{} ]", Block(body)),
+        }
+    }
+}
+
+/// Displays a block of nodes, each preceded by a space.
+struct Block<'a>(&'a [Node]);
+
+impl<'a> fmt::Display for Block<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for node in self.0 {
+            write!(f, " {}", node)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tokens with a meaning of their own, which can't name user-defined words.
+const KEYWORDS: &[&str] = &[":", ";", "mode", "if", "else", "then", "begin", "until",
+                            "do", "loop", "i", "unset", "[", "]", "{", "}"];
+
+/// A token, and the column where it starts.
+#[derive(Clone, Copy, Debug)]
+pub struct Token<'a> {
+    pub text: &'a str,
+    pub column: usize,
+}
+
+/// Splits a line into tokens, counting columns in characters. A token
+/// starting with `"` is a string, which runs to the closing `"`, spaces and
+/// all.
+pub fn tokenize(buf: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut chars = buf.char_indices().enumerate().peekable();
+    while let Some((column, (begin, c))) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let mut end = buf.len();
+        if c == '"' {
+            let mut escaped = false;
+            for (_, (i, c)) in chars.by_ref() {
+                if c == '"' && !escaped {
+                    end = i + 1;
+                    break;
+                }
+                escaped = c == '\\' && !escaped;
+            }
+        } else {
+            while let Some(&(_, (i, c))) = chars.peek() {
+                if c.is_whitespace() {
+                    end = i;
+                    break;
+                }
+                chars.next();
+            }
+        }
+        tokens.push(Token { text: &buf[begin..end], column });
+    }
+    tokens
+}
+
+pub fn syntax(token: Token, message: String) -> rpn::Error {
+    rpn::Error::Syntax(rpn::SyntaxError {
+        token: token.text.to_string(),
+        column: token.column,
+        message,
+    })
+}
+
+/// Parses a line into a block of nodes, given the operators and the words
+/// defined so far.
+pub fn parse_line(ops: &Registry, words: &HashMap<String, Rc<Word>>, buf: &str) -> rpn::Result<Vec<Node>> {
+    let mut parser = Parser {
+        tokens: tokenize(buf).into_iter(),
+        ops,
+        words,
+        defined: Vec::new(),
+        defining: None,
+        depth: 0,
     };
-    Ok(())
+
+    match parser.parse_block(&[], 0, true)? {
+        (nodes, None) => Ok(nodes),
+        (_, Some(token)) => Err(syntax(token, format!("unexpected `{}`", token.text))),
+    }
+}
+
+/// Turns the tokens of a line into nodes, checking that every word it uses
+/// is known.
+struct Parser<'a, 'w> {
+    tokens: vec::IntoIter<Token<'a>>,
+    ops: &'w Registry,
+    /// The words defined before this line.
+    words: &'w HashMap<String, Rc<Word>>,
+    /// The words defined earlier on this line.
+    defined: Vec<&'a str>,
+    /// The word whose body is being parsed, if any.
+    defining: Option<&'a str>,
+    /// How many blocks, quotations and lists enclose the current token.
+    depth: usize,
+}
+
+impl<'a, 'w> Parser<'a, 'w> {
+    /// Parses nodes until one of the `ends` tokens, which is returned, or
+    /// the end of the line. `loops` counts the enclosing `do` loops, and
+    /// definitions are only allowed at the `top` level.
+    fn parse_block(&mut self, ends: &[&str], loops: usize, top: bool)
+        -> rpn::Result<(Vec<Node>, Option<Token<'a>>)>
+    {
+        let mut nodes = Vec::new();
+
+        while let Some(token) = self.tokens.next() {
+            if ends.contains(&token.text) {
+                return Ok((nodes, Some(token)));
+            }
+            let node = match token.text {
+                ":" if top => {
+                    let name = match self.tokens.next() {
+                        Some(name) if is_name(name.text) && self.ops.get(name.text).is_none() => name.text,
+                        Some(name) => return Err(syntax(name, format!("can't define `{}`", name.text))),
+                        None => return Err(syntax(token, "missing name after `:`".to_string())),
+                    };
+                    self.defining = Some(name);
+                    let body = self.parse_until(token, ";", 0)?;
+                    self.defining = None;
+                    self.defined.push(name);
+                    Node::Define(name.to_string(), Rc::new(body))
+                },
+                ":" => return Err(syntax(token, "definitions can't be nested".to_string())),
+                "mode" => {
+                    let name = match self.tokens.next() {
+                        Some(name) => name,
+                        None => return Err(syntax(token, "missing name after `mode`".to_string())),
+                    };
+                    match name.text.parse() {
+                        Ok(arith) => Node::Mode(arith),
+                        Err(_) => return Err(syntax(name, format!("unknown mode `{}`", name.text))),
+                    }
+                },
+                "if" => {
+                    match self.nested(token, |p| p.parse_block(&["else", "then"], loops, false))? {
+                        (then, Some(end)) if end.text == "else" => {
+                            Node::If(then, self.parse_until(token, "then", loops)?)
+                        },
+                        (then, Some(_)) => Node::If(then, Vec::new()),
+                        (_, None) => return Err(syntax(token, "`if` without `then`".to_string())),
+                    }
+                },
+                "begin" => Node::BeginUntil(self.parse_until(token, "until", loops)?),
+                "do" => Node::DoLoop(self.parse_until(token, "loop", loops + 1)?),
+                "unset" => {
+                    match self.tokens.next() {
+                        Some(name) => Node::Unset(var_name(name, name.text)?),
+                        None => return Err(syntax(token, "missing name after `unset`".to_string())),
+                    }
+                },
+                word if self.ops.get(word).is_none() && word.starts_with('!') => {
+                    Node::Store(var_name(token, &word[1..])?)
+                },
+                word if word.starts_with('@') => Node::Fetch(var_name(token, &word[1..])?),
+                // A quotation runs on its own, outside any loop around it.
+                "[" => Node::Quote(Rc::new(self.parse_until(token, "]", 0)?)),
+                "{" => Node::List(self.parse_list(token)?),
+                "i" if loops > 0 => Node::Index,
+                "i" => return Err(syntax(token, "`i` outside of a `do` loop".to_string())),
+                ";" | "else" | "then" | "until" | "loop" | "]" | "}" => {
+                    return Err(syntax(token, format!("unexpected `{}`", token.text)));
+                },
+                word if self.ops.get(word).is_some() => Node::Op(self.ops.get(word).expect("just looked up").clone()),
+                word if self.is_known(word) => Node::Word(word.to_string()),
+                word if word.starts_with('"') && (word.len() < 2 || !word.ends_with('"')) => {
+                    return Err(syntax(token, "unterminated string".to_string()));
+                },
+                word if word.starts_with('"') => {
+                    return Err(syntax(token, format!("bad escape in string {}", word)));
+                },
+                word => {
+                    // A definition may only use words that exist, but an
+                    // unknown word anywhere else is just bad input.
+                    return Err(match self.defining {
+                        Some(_) => rpn::Error::Undefined(word.to_string()),
+                        None => syntax(token, format!("unknown word `{}`", word)),
+                    });
+                },
+            };
+            nodes.push(node);
+        }
+        Ok((nodes, None))
+    }
+
+    /// Parses the body of a construct opened by `start`, which must be
+    /// closed by `end`.
+    fn parse_until(&mut self, start: Token<'a>, end: &str, loops: usize) -> rpn::Result<Vec<Node>> {
+        match self.nested(start, |p| p.parse_block(&[end], loops, false))? {
+            (nodes, Some(_)) => Ok(nodes),
+            (_, None) => Err(syntax(start, format!("`{}` without `{}`", start.text, end))),
+        }
+    }
+
+    /// Parses the values of a list opened by `start`, up to its `}`.
+    fn parse_list(&mut self, start: Token<'a>) -> rpn::Result<Vec<Node>> {
+        self.nested(start, |p| p.parse_items(start))
+    }
+
+    /// The values of a list, for `parse_list`.
+    fn parse_items(&mut self, start: Token<'a>) -> rpn::Result<Vec<Node>> {
+        let mut items = Vec::new();
+        while let Some(token) = self.tokens.next() {
+            let item = match token.text {
+                "}" => return Ok(items),
+                "{" => Node::List(self.parse_list(token)?),
+                "[" => Node::Quote(Rc::new(self.parse_until(token, "]", 0)?)),
+                text if self.ops.get(text).is_none() && list_item(text).is_some() => {
+                    Node::Word(text.to_string())
+                },
+                text => return Err(syntax(token, format!("`{}` can't go in a list", text))),
+            };
+            items.push(item);
+        }
+        Err(syntax(start, "`{` without `}`".to_string()))
+    }
+
+    /// Runs `parse` one level deeper inside the construct opened by `start`,
+    /// failing if that is more than `MAX_NESTING` levels deep.
+    fn nested<T, F>(&mut self, start: Token<'a>, parse: F) -> rpn::Result<T>
+        where F: FnOnce(&mut Self) -> rpn::Result<T>
+    {
+        if self.depth == MAX_NESTING {
+            return Err(syntax(start, format!("`{}` nested more than {} deep", start.text, MAX_NESTING)));
+        }
+        self.depth += 1;
+        let res = parse(self);
+        self.depth -= 1;
+        res
+    }
+
+    /// Whether a word is a literal, or a word defined before or
+    /// (recursively) being defined now.
+    fn is_known(&self, word: &str) -> bool {
+        literal(word).is_some() ||
+            self.words.contains_key(word) || self.defined.contains(&word) ||
+            self.defining == Some(word)
+    }
+}
+
+/// Checks that `name`, which comes from `token`, can name a variable: either
+/// a register `0` to `9`, or anything that could name a word.
+pub fn var_name(token: Token, name: &str) -> rpn::Result<String> {
+    let register = name.len() == 1 && name.chars().all(|c| c.is_ascii_digit());
+    if name.is_empty() {
+        Err(syntax(token, format!("missing name after `{}`", token.text)))
+    } else if register || is_word_name(name) {
+        Ok(name.to_string())
+    } else {
+        Err(syntax(token, format!("can't name a variable `{}`", name)))
+    }
+}
+
+/// Parses a token as a literal value, if it is one: a boolean, an integer
+/// (`12`, or a big integer if it does not fit), a fraction (`7/3`), a
+/// floating-point number (`3.5`, `1e-3`) or a string (`"a \"b\""`).
+pub fn literal(token: &str) -> Option<rpn::Elt> {
+    if token.starts_with('"') {
+        return string(token).map(rpn::Elt::Str);
+    }
+    match token {
+        //"true" | "false" => Some(Elt::Bool(bool::from_str(token).unwrap())),
+        "true" => Some(rpn::Elt::Bool(true)),
+        "false" => Some(rpn::Elt::Bool(false)),
+        _ => {
+            if let Ok(n) = i32::from_str(token) {
+                return Some(rpn::Elt::Int(n));
+            }
+            if let Ok(n) = BigInt::from_str(token) {
+                return Some(rpn::Elt::from_big(n));
+            }
+            if let Ok(r) = Rational::from_str(token) {
+                return Some(rpn::Elt::Rational(r));
+            }
+            // Only accept plain decimal notation, so that words like `inf`
+            // or `NaN` are not taken for numbers.
+            let decimal = token.chars().all(|c| c.is_ascii_digit() || "+-.eE".contains(c)) &&
+                          token.chars().any(|c| c.is_ascii_digit());
+            if decimal {
+                return f64::from_str(token).ok().map(rpn::Elt::Float);
+            }
+            None
+        },
+    }
+}
+
+/// Reads a value in a list: a literal, or one of the floats `inf`, `-inf`
+/// and `NaN`, which have no literals but are written that way in lists.
+pub fn list_item(token: &str) -> Option<rpn::Elt> {
+    match token {
+        "inf" | "-inf" | "NaN" => f64::from_str(token).ok().map(rpn::Elt::Float),
+        _ => literal(token),
+    }
+}
+
+/// Reads a quoted string, undoing the escapes `\"`, `\\`, `\n` and `\t`.
+fn string(token: &str) -> Option<String> {
+    if token.len() < 2 || !token.starts_with('"') || !token.ends_with('"') {
+        return None;
+    }
+    let mut s = String::new();
+    let mut chars = token[1..token.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        s.push(match c {
+            '\\' => {
+                match chars.next() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    _ => return None,
+                }
+            },
+            '"' => return None,
+            c => c,
+        });
+    }
+    Some(s)
+}
+
+/// Whether a token may be used as a name, of a word or an operator, as long
+/// as no operator has it already. Tokens starting with `!` or `@` are taken
+/// for variable stores and fetches, those like `=x` for infix lines, and the
+/// REPL's commands for themselves.
+pub fn is_name(token: &str) -> bool {
+    !KEYWORDS.contains(&token) && !COMMANDS.contains(&token) && literal(token).is_none() &&
+        infix_expr(token).is_none() &&
+        !token.starts_with('!') && !token.starts_with('@') && !token.starts_with('"')
+}
+
+/// Whether a token may be used as the name of a word, or of a variable,
+/// given the built-in operators.
+pub fn is_word_name(token: &str) -> bool {
+    is_name(token) && rpn::Op::from_token(token).is_none()
 }
 
 #[cfg(test)]
 mod tests {
-    use rpn::{Stack, Error, Elt};
-    use parser::evaluate_line;
+    use rpn::{Arith, Error, Elt};
+    use bigint::BigInt;
+    use parser::{evaluate_line, repl, run_script, Session, MAX_NESTING};
+    use rational::Rational;
+    use std::{env, fs, process};
+    use std::str::FromStr;
 
     #[test]
     fn test_evaluate_line_bool() {
-        let mut stack = Stack::new();
+        let mut session = Session::new();
         let s = "true".to_string();
-        assert!(evaluate_line(&mut stack, &s).is_ok());
-        assert_eq!(stack.pop().unwrap(), Elt::Bool(true));
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Bool(true));
         let s = "false".to_string();
-        assert!(evaluate_line(&mut stack, &s).is_ok());
-        assert_eq!(stack.pop().unwrap(), Elt::Bool(false));
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Bool(false));
     }
 
     #[test]
     fn test_evaluate_line_int() {
-        let mut stack = Stack::new();
+        let mut session = Session::new();
         let s = "12".to_string();
-        assert!(evaluate_line(&mut stack, &s).is_ok());
-        assert_eq!(stack.pop().unwrap(), Elt::Int(12));
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(12));
     }
 
     #[test]
     fn test_evaluate_line_plus() {
-        let mut stack = Stack::new();
+        let mut session = Session::new();
         let s = "12".to_string();
-        assert!(evaluate_line(&mut stack, &s).is_ok());
+        assert!(evaluate_line(&mut session, &s).is_ok());
         let s = "13".to_string();
-        assert!(evaluate_line(&mut stack, &s).is_ok());
+        assert!(evaluate_line(&mut session, &s).is_ok());
         let s = "+".to_string();
-        assert!(evaluate_line(&mut stack, &s).is_ok());
-        assert_eq!(stack.pop().unwrap(), Elt::Int(25));
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(25));
     }
 
     #[test]
     fn test_evaluate_line_neg() {
-        let mut stack = Stack::new();
+        let mut session = Session::new();
         let s = "false".to_string();
-        assert!(evaluate_line(&mut stack, &s).is_ok());
+        assert!(evaluate_line(&mut session, &s).is_ok());
         let s = "~".to_string();
-        assert!(evaluate_line(&mut stack, &s).is_ok());
-        assert_eq!(stack.pop().unwrap(), Elt::Bool(true));
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Bool(true));
     }
 
     #[test]
     fn test_evaluate_line_swap() {
-        let mut stack = Stack::new();
+        let mut session = Session::new();
         let s = "false".to_string();
-        assert!(evaluate_line(&mut stack, &s).is_ok());
+        assert!(evaluate_line(&mut session, &s).is_ok());
         let s = "15".to_string();
-        assert!(evaluate_line(&mut stack, &s).is_ok());
+        assert!(evaluate_line(&mut session, &s).is_ok());
         let s = "<->".to_string();
-        assert!(evaluate_line(&mut stack, &s).is_ok());
-        assert_eq!(stack.pop().unwrap(), Elt::Bool(false));
-        assert_eq!(stack.pop().unwrap(), Elt::Int(15));
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Bool(false));
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(15));
     }
 
     #[test]
     fn test_evaluate_line_eq() {
-        let mut stack = Stack::new();
+        let mut session = Session::new();
         let s = "12".to_string();
-        assert!(evaluate_line(&mut stack, &s).is_ok());
+        assert!(evaluate_line(&mut session, &s).is_ok());
         let s = "15".to_string();
-        assert!(evaluate_line(&mut stack, &s).is_ok());
+        assert!(evaluate_line(&mut session, &s).is_ok());
         let s = "=".to_string();
-        assert!(evaluate_line(&mut stack, &s).is_ok());
-        assert_eq!(stack.pop().unwrap(), Elt::Bool(false));
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Bool(false));
     }
 
     #[test]
     fn test_evaluate_line_rand() {
-        let mut stack = Stack::new();
+        let mut session = Session::new();
         let s = "12".to_string();
-        assert!(evaluate_line(&mut stack, &s).is_ok());
+        assert!(evaluate_line(&mut session, &s).is_ok());
         let s = "#".to_string();
-        assert!(evaluate_line(&mut stack, &s).is_ok());
-        let res = stack.pop();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        let res = session.stack.pop();
         assert!(res.is_ok());
         let res = res.unwrap();
         assert!(res >= Elt::Int(0));
@@ -145,21 +1115,801 @@ mod tests {
 
     #[test]
     fn test_evaluate_line_quit() {
-        let mut stack = Stack::new();
+        let mut session = Session::new();
         let s = "quit".to_string();
-        let res = evaluate_line(&mut stack, &s);
+        let res = evaluate_line(&mut session, &s);
         assert!(res.is_err());
         if let Err(Error::Quit) = res {
-        } else { assert!(false); }
+        } else { panic!(); }
     }
 
     #[test]
     fn test_evaluate_line_bad_parse() {
-        let mut stack = Stack::new();
+        let mut session = Session::new();
         let s = "~false".to_string();
-        let res = evaluate_line(&mut stack, &s);
+        let res = evaluate_line(&mut session, &s);
+        assert!(res.is_err());
+        if let Err(Error::Syntax(_)) = res {
+        } else { panic!(); }
+    }
+
+    #[test]
+    fn test_evaluate_line_define() {
+        let mut session = Session::new();
+        let s = ": twice 2 + ;".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert!(session.stack.pop().is_err());
+        let s = "3 twice".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(5));
+    }
+
+    #[test]
+    fn test_evaluate_line_define_nested() {
+        let mut session = Session::new();
+        let s = ": twice 2 + ; : four twice twice ;".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        let s = "1 four".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(5));
+    }
+
+    #[test]
+    fn test_evaluate_line_define_undefined() {
+        let mut session = Session::new();
+        let s = ": twice 2 plus ;".to_string();
+        let res = evaluate_line(&mut session, &s);
         assert!(res.is_err());
-        if let Err(Error::Syntax) = res {
-        } else { assert!(false); }
+        if let Err(Error::Undefined(ref word)) = res {
+            assert_eq!(word, "plus");
+        } else { panic!(); }
+        let s = "twice".to_string();
+        let res = evaluate_line(&mut session, &s);
+        if let Err(Error::Syntax(_)) = res {
+        } else { panic!(); }
+    }
+
+    #[test]
+    fn test_evaluate_line_define_recursive() {
+        let mut session = Session::new();
+        let s = ": forever 1 forever ;".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        let s = "forever".to_string();
+        let res = evaluate_line(&mut session, &s);
+        assert!(res.is_err());
+        if let Err(Error::Recursion(ref word)) = res {
+            assert_eq!(word, "forever");
+        } else { panic!(); }
+    }
+
+    #[test]
+    fn test_evaluate_line_define_bad_syntax() {
+        let mut session = Session::new();
+        for s in &[": twice 2 +", ": ;", ": + 1 ;", ": 12 1 ;", "1 ;", ": a : b ; ;"] {
+            let res = evaluate_line(&mut session, s);
+            if let Err(Error::Syntax(_)) = res {
+            } else { panic!("{}", s); }
+        }
+    }
+
+    #[test]
+    fn test_evaluate_line_arith() {
+        let mut session = Session::new();
+        let s = "7 2 - 3 * 4 / 2 %".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(1));
+        let s = "1 0 /".to_string();
+        let res = evaluate_line(&mut session, &s);
+        if let Err(Error::DivideByZero) = res {
+        } else { panic!(); }
+    }
+
+    #[test]
+    fn test_evaluate_line_logic() {
+        let mut session = Session::new();
+        let s = "1 2 < 2 2 >= and 1 2 != not or".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Bool(true));
+        let s = "3 2 <= 3 2 > and".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Bool(false));
+    }
+
+    #[test]
+    fn test_evaluate_line_mode() {
+        let mut session = Session::new();
+        let s = "mode checked 2147483647 1 +".to_string();
+        let res = evaluate_line(&mut session, &s);
+        if let Err(Error::Overflow) = res {
+        } else { panic!(); }
+        assert!(session.stack.pop().is_err());
+
+        let s = "mode wrapping 2147483647 1 +".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.arith(), Arith::Wrapping);
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(-2147483648));
+
+        let s = "mode saturating 2147483647 1 +".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.arith(), Arith::Saturating);
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(2147483647));
+
+        let s = "mode fast".to_string();
+        let res = evaluate_line(&mut session, &s);
+        if let Err(Error::Syntax(_)) = res {
+        } else { panic!(); }
+        assert_eq!(session.stack.arith(), Arith::Saturating);
+    }
+
+    #[test]
+    fn test_evaluate_line_rollback() {
+        let mut session = Session::new();
+        let s = "1 2".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+
+        let s = "+ true + 5".to_string();
+        let res = evaluate_line(&mut session, &s);
+        if let Err(Error::Type(_)) = res {
+        } else { panic!(); }
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(2));
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(1));
+        assert!(session.stack.pop().is_err());
+    }
+
+    #[test]
+    fn test_evaluate_line_rollback_definitions() {
+        let mut session = Session::new();
+        let s = "mode wrapping : one 1 ; one +".to_string();
+        let res = evaluate_line(&mut session, &s);
+        if let Err(Error::Underflow(_)) = res {
+        } else { panic!(); }
+        assert_eq!(session.stack.arith(), Arith::Bignum);
+        assert!(session.words.is_empty());
+        assert!(session.stack.pop().is_err());
+    }
+
+    #[test]
+    fn test_evaluate_line_float() {
+        let mut session = Session::new();
+        let s = "3.5 1e1 +".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Float(13.5));
+        let s = "1.".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Float(1.0));
+        for s in &["inf", "NaN", "1e", "-."] {
+            if let Err(Error::Syntax(_)) = evaluate_line(&mut session, s) {
+            } else { panic!("{}", s); }
+        }
+    }
+
+    #[test]
+    fn test_evaluate_line_rational() {
+        let mut session = Session::new();
+        let s = "7/3 -1/3 + 2 *".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Rational(Rational::from_int(4)));
+        let s = "1/0".to_string();
+        if let Err(Error::Syntax(_)) = evaluate_line(&mut session, &s) {
+        } else { panic!(); }
+    }
+
+    #[test]
+    fn test_evaluate_line_convert() {
+        let mut session = Session::new();
+        let s = "7/2 >int 7/2 >float 0.25 >rat 3 >rat".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Rational(Rational::from_int(3)));
+        assert_eq!(session.stack.pop().unwrap(), Elt::Rational(Rational::new(1, 4).unwrap()));
+        assert_eq!(session.stack.pop().unwrap(), Elt::Float(3.5));
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(3));
+    }
+
+    #[test]
+    fn test_evaluate_line_bignum() {
+        let mut session = Session::new();
+        let s = "mode checked 2 100 **".to_string();
+        let res = evaluate_line(&mut session, &s);
+        if let Err(Error::Overflow) = res {
+        } else { panic!(); }
+
+        // Integers are promoted by default.
+        let s = "2 100 ** 1 -".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        let expected = BigInt::from_str("1267650600228229401496703205375").unwrap();
+        assert_eq!(session.stack.pop().unwrap(), Elt::Big(expected));
+
+        let s = "2147483647 1 + 1 -".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(2147483647));
+    }
+
+    #[test]
+    fn test_evaluate_line_big_literal() {
+        let mut session = Session::new();
+        let s = "-99999999999999999999 99999999999999999998 +".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(-1));
+    }
+
+    #[test]
+    fn test_evaluate_line_if() {
+        let mut session = Session::new();
+        let s = "1 2 < if 10 else 20 then".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(10));
+        let s = "1 2 > if 10 else 20 then".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(20));
+        let s = "5 false if 1 + then".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(5));
+        let s = "5 if 1 then".to_string();
+        let res = evaluate_line(&mut session, &s);
+        if let Err(Error::Type(_)) = res {
+        } else { panic!(); }
+    }
+
+    #[test]
+    fn test_evaluate_line_begin_until() {
+        let mut session = Session::new();
+        let s = "true false false 0 begin 1 + <-> until".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(3));
+        assert!(session.stack.pop().is_err());
+    }
+
+    #[test]
+    fn test_evaluate_line_do_loop() {
+        let mut session = Session::new();
+        let s = "0 5 1 do i + loop".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(10));
+        let s = "0 3 0 do 3 0 do i + loop loop".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(9));
+        let s = "7 1 5 do 0 + loop".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(7));
+    }
+
+    #[test]
+    fn test_evaluate_line_define_control() {
+        let mut session = Session::new();
+        let s = ": fact 1 <-> 1 + 1 do i * loop ;".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        let s = "10 fact".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(3628800));
+        let s = ": double if 2 * false double then ;".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        let s = "3 true double".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(6));
+    }
+
+    #[test]
+    fn test_evaluate_line_bad_nesting() {
+        let mut session = Session::new();
+        let cases = [("true if 1", "if", 5, "`if` without `then`"),
+                     ("1 then", "then", 2, "unexpected `then`"),
+                     ("begin 1", "begin", 0, "`begin` without `until`"),
+                     ("3 0 do i", "do", 4, "`do` without `loop`"),
+                     ("true if 1 loop", "loop", 10, "unexpected `loop`"),
+                     ("i", "i", 0, "`i` outside of a `do` loop"),
+                     ("true if : x 1 ; then", ":", 8, "definitions can't be nested"),
+                     (": x if 1 ;", ";", 9, "unexpected `;`"),
+                     ("1  frob", "frob", 3, "unknown word `frob`")];
+        for &(s, token, column, message) in &cases {
+            let res = evaluate_line(&mut session, s);
+            if let Err(Error::Syntax(ref err)) = res {
+                assert_eq!(err.token, token);
+                assert_eq!(err.column, column);
+                assert_eq!(err.message, message);
+            } else { panic!("{}", s); }
+        }
+    }
+
+    #[test]
+    fn test_evaluate_line_bad_parse_position() {
+        let mut session = Session::new();
+        let s = "1 2 ~false".to_string();
+        let res = evaluate_line(&mut session, &s);
+        if let Err(Error::Syntax(ref err)) = res {
+            assert_eq!(err.token, "~false");
+            assert_eq!(err.column, 4);
+        } else { panic!(); }
+        assert_eq!(res.unwrap_err().to_string(), "syntax error at column 5: unknown word `~false`");
+
+        let s = "mode fast".to_string();
+        if let Err(Error::Syntax(ref err)) = evaluate_line(&mut session, &s) {
+            assert_eq!(err.token, "fast");
+            assert_eq!(err.message, "unknown mode `fast`");
+        } else { panic!(); }
+    }
+
+    #[test]
+    fn test_evaluate_line_fault() {
+        let mut session = Session::new();
+        let s = "1 2 if 3 then".to_string();
+        let res = evaluate_line(&mut session, &s);
+        if let Err(Error::Type(ref fault)) = res {
+            assert_eq!(fault.op, "if");
+            assert_eq!(fault.stack, vec![Elt::Int(1), Elt::Int(2)]);
+        } else { panic!(); }
+
+        let s = "1 do loop".to_string();
+        let res = evaluate_line(&mut session, &s);
+        if let Err(Error::Underflow(ref fault)) = res {
+            assert_eq!(fault.op, "do");
+            assert_eq!(fault.stack, vec![Elt::Int(1)]);
+        } else { panic!(); }
+    }
+
+    #[test]
+    fn test_run_script() {
+        let script = b"#!/usr/bin/env hw04\n1 2 +\n\n: sq 2 ** ;\n4 sq 7/2\n";
+        let mut output = Vec::new();
+        assert!(run_script(&mut Session::new(), &script[..], &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(), "3\n16\n7/2\n");
+    }
+
+    #[test]
+    fn test_run_script_quit() {
+        let script = b"1\nquit\n2\n";
+        let mut output = Vec::new();
+        assert!(run_script(&mut Session::new(), &script[..], &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(), "1\n");
+    }
+
+    #[test]
+    fn test_run_script_error() {
+        let script = b"1 2 +\n#!\n3\n";
+        let mut output = Vec::new();
+        let res = run_script(&mut Session::new(), &script[..], &mut output);
+        let err = res.unwrap_err();
+        assert_eq!(err.line, 2);
+        if let Error::Syntax(_) = err.error {
+        } else { panic!(); }
+        assert_eq!(err.to_string(), "line 2: syntax error at column 1: unknown word `#!`");
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_repl_eof() {
+        let mut output = Vec::new();
+        assert!(repl(&mut Session::new(), &b"1 2 +\n"[..], &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(), ">   3\n> \n");
+    }
+
+    #[test]
+    fn test_repl_quit() {
+        let mut output = Vec::new();
+        assert!(repl(&mut Session::new(), &b"1 2 +\n3 quit\n4\n"[..], &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(), ">   3\n> ");
+    }
+
+    #[test]
+    fn test_repl_errors() {
+        let mut output = Vec::new();
+        assert!(repl(&mut Session::new(), &b"1 frob\n"[..], &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   ">     ^\n  syntax error at column 3: unknown word `frob`\n> \n");
+    }
+
+    #[test]
+    fn test_repl_peek() {
+        let mut output = Vec::new();
+        assert!(repl(&mut Session::new(), &b"1\n2\n+\n"[..], &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(), ">   1\n>   2\n>   3\n> \n");
+    }
+
+    #[test]
+    fn test_repl_show_stack() {
+        let mut output = Vec::new();
+        assert!(repl(&mut Session::new(), &b"1 true 3\n.s\n"[..], &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   ">   3\n>   2: 1\n  1: true\n  0: 3\n  (undo 1/100, redo 0)\n> \n");
+    }
+
+    #[test]
+    fn test_repl_display() {
+        let mut output = Vec::new();
+        let input = b"display none\n1 2\ndisplay stack\n3\ndisplay all\n";
+        assert!(repl(&mut Session::new(), &input[..], &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "> > > >   2: 1\n  1: 2\n  0: 3\n\
+                    >           ^\n  syntax error at column 9: unknown display mode `all`\n> \n");
+    }
+
+    #[test]
+    fn test_evaluate_line_stack_words() {
+        let mut session = Session::new();
+        let s = ": square dup * ; 1 2 3 rot -rot over tuck nip depth 1 pick drop".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.values(),
+                   &[Elt::Int(1), Elt::Int(2), Elt::Int(2), Elt::Int(2), Elt::Int(4)]);
+        let s = "3 roll 2 pick square clear 4 square".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.values(), &[Elt::Int(16)]);
+    }
+
+    #[test]
+    fn test_evaluate_line_vars() {
+        let mut session = Session::new();
+        let s = "42 !x 7 !0 @x @0 -".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(35));
+        assert_eq!(session.vars.fetch("x").unwrap(), Elt::Int(42));
+
+        let s = ": bump @x 1 + !x ; bump bump @x".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Int(44));
+
+        let s = "unset x @0".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        let s = "@x".to_string();
+        let res = evaluate_line(&mut session, &s);
+        if let Err(Error::Unbound(ref name)) = res {
+            assert_eq!(name, "x");
+        } else { panic!(); }
+        let s = "unset x".to_string();
+        let res = evaluate_line(&mut session, &s);
+        if let Err(Error::Unbound(_)) = res { } else { panic!(); }
+
+        // `!=` is still inequality.
+        let s = "1 2 !=".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.pop().unwrap(), Elt::Bool(true));
+    }
+
+    #[test]
+    fn test_evaluate_line_vars_rollback() {
+        let mut session = Session::new();
+        let s = "1 !x".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        let s = "2 !x 3 !y unset x !z".to_string();
+        let res = evaluate_line(&mut session, &s);
+        if let Err(Error::Underflow(ref fault)) = res {
+            assert_eq!(fault.op, "!z");
+        } else { panic!(); }
+        assert_eq!(session.vars.fetch("x").unwrap(), Elt::Int(1));
+        assert!(session.vars.fetch("y").is_err());
+    }
+
+    #[test]
+    fn test_evaluate_line_bad_var_name() {
+        let mut session = Session::new();
+        for &(s, message) in &[("1 !12", "can't name a variable `12`"),
+                               ("@if", "can't name a variable `if`"),
+                               ("1 !", "missing name after `!`"),
+                               ("unset", "missing name after `unset`"),
+                               ("1 !=x", "can't name a variable `=x`"),
+                               (": =x 1 ;", "can't define `=x`"),
+                               (": @x 1 ;", "can't define `@x`"),
+                               (": undo 1 ;", "can't define `undo`"),
+                               (": check 1 ;", "can't define `check`")] {
+            let res = evaluate_line(&mut session, s);
+            if let Err(Error::Syntax(ref err)) = res {
+                assert_eq!(err.message, message);
+            } else { panic!("{}", s); }
+        }
+    }
+
+    #[test]
+    fn test_repl_vars() {
+        let mut output = Vec::new();
+        assert!(repl(&mut Session::new(), &b"2 !b 1/2 !a 3 !1\nvars\n"[..], &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "> >   1 = 3\n  a = 1/2\n  b = 2\n> \n");
+    }
+
+    #[test]
+    fn test_session_save_load() {
+        let mut session = Session::new();
+        let s = "mode wrapping 1 true 2.5 1e300 -0.0 7/3 99999999999999999999 1e300 dup * !inf".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        let s = "3 !0 : sq dup * ; : quad sq sq ; : count 0 do i if 1 else 2 then loop ;".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+
+        let mut saved = Vec::new();
+        assert!(session.save(&mut saved).is_ok());
+        let text = String::from_utf8(saved.clone()).unwrap();
+        assert!(text.contains("stack float 2.5\n"));
+        assert!(text.contains("var inf float inf\n"));
+        assert!(text.contains(": count 0 do i if 1 else 2 then loop ;\n"));
+
+        let mut loaded = Session::new();
+        loaded.load(&saved[..]).unwrap();
+        assert_eq!(loaded.stack.arith(), Arith::Wrapping);
+        assert_eq!(loaded.stack.values(), session.stack.values());
+        assert_eq!(loaded.vars.list().collect::<Vec<_>>(), session.vars.list().collect::<Vec<_>>());
+        let mut resaved = Vec::new();
+        assert!(loaded.save(&mut resaved).is_ok());
+        assert_eq!(resaved, saved);
+
+        let s = "clear 3 quad".to_string();
+        assert!(evaluate_line(&mut loaded, &s).is_ok());
+        assert_eq!(loaded.stack.values(), &[Elt::Int(81)]);
+    }
+
+    #[test]
+    fn test_session_load_keeps() {
+        let mut session = Session::new();
+        session.stack.set_seed(7);
+        let mut other = Session::new();
+        other.stack.set_seed(7);
+        assert!(evaluate_line(&mut session, "1 2 !x : inc 1 + ;").is_ok());
+        // A bad file changes nothing.
+        if let Err(Error::Corrupt(2, _)) = session.load("stack int 5\nstack int x\n".as_bytes()) {
+        } else {
+            panic!();
+        }
+        assert_eq!(session.stack.values(), &[Elt::Int(1)]);
+        assert!(evaluate_line(&mut session, "@x inc drop").is_ok());
+        // A good one replaces the values and words, but not the state of `#`.
+        assert!(session.load("mode wrapping\nstack int 5\n".as_bytes()).is_ok());
+        assert_eq!(session.stack.arith(), Arith::Wrapping);
+        assert!(session.words.is_empty());
+        assert!(evaluate_line(&mut session, "drop 1000 #").is_ok());
+        assert!(evaluate_line(&mut other, "1000 #").is_ok());
+        assert_eq!(session.stack.values(), other.stack.values());
+    }
+
+    #[test]
+    fn test_session_load_corrupt() {
+        for &(text, line, message) in &[
+            ("stack int 1\nstack int x\n", 2, "bad int `x`"),
+            ("# comment\n\nstack rat 1/0\n", 3, "bad rat `1/0`"),
+            ("stack frob 1\n", 1, "bad frob `1`"),
+            ("mode fast\n", 1, "unknown mode `fast`"),
+            ("var 12 int 1\n", 1, "syntax error at column 1: can't name a variable `12`"),
+            ("push 1\n", 1, "can't understand `push 1`"),
+            (": sq dup * \n", 1, "syntax error at column 1: `:` without `;`"),
+            (": sq dup * ; 3\n", 1, "expected a single definition"),
+        ] {
+            let res = Session::new().load(text.as_bytes());
+            if let Err(Error::Corrupt(n, ref m)) = res {
+                assert_eq!((n, m.as_str()), (line, message));
+            } else { panic!("{}", text); }
+        }
+    }
+
+    #[test]
+    fn test_repl_save_load() {
+        let path = env::temp_dir().join(format!("hw04-test-{}.session", process::id()));
+        let path = path.to_str().unwrap();
+        let mut output = Vec::new();
+        let input = format!("1 2 !x : inc 1 + ;\nsave {}\n", path);
+        assert!(repl(&mut Session::new(), input.as_bytes(), &mut output).is_ok());
+
+        let mut output = Vec::new();
+        let input = format!("load {}\n@x inc\nload {}.missing\n.s\n", path, path);
+        assert!(repl(&mut Session::new(), input.as_bytes(), &mut output).is_ok());
+        fs::remove_file(path).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("> >   3\n>   I/O error: "), "{}", output);
+        assert!(output.ends_with(">   1: 1\n  0: 3\n  (undo 2/100, redo 0)\n> \n"), "{}", output);
+    }
+
+    #[test]
+    fn test_repl_undo_redo() {
+        let mut output = Vec::new();
+        let input = b"1 2\n+\n.s\nundo\nundo\nundo\nredo\n*\nredo\n.s\n";
+        assert!(repl(&mut Session::new(), &input[..], &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   ">   2\n>   3\n>   0: 3\n  (undo 2/100, redo 0)\n\
+                    >   2\n> >   nothing to undo\n>   2\n>   2\n>   nothing to redo\n\
+                    >   0: 2\n  (undo 2/100, redo 0)\n> \n");
+    }
+
+    #[test]
+    fn test_repl_undo_depth() {
+        let mut output = Vec::new();
+        let input = b"undo-depth 1\n1\n2\n.s\nundo\nundo\n1 frob\nundo-depth x\n";
+        assert!(repl(&mut Session::new(), &input[..], &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "> >   1\n>   2\n>   1: 1\n  0: 2\n  (undo 1/1, redo 0)\n\
+                    >   1\n>   nothing to undo\n\
+                    >     ^\n  syntax error at column 3: unknown word `frob`\n\
+                    >              ^\n  syntax error at column 12: bad undo depth `x`\n> \n");
+    }
+
+    #[test]
+    fn test_repl_disasm() {
+        let mut output = Vec::new();
+        let input = b": sq dup * ;\ndisasm 2 sq if 1 then\nsee sq\n.s\ndisasm frob\nsee dup\n";
+        assert!(repl(&mut Session::new(), &input[..], &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   concat!("> ",
+                           ">      0  push 2\n     1  call sq\n     2  jump-unless 4\n     3  push 1\n",
+                           ">      0  op dup\n     1  op *\n",
+                           ">   (undo 0/100, redo 0)\n",
+                           ">          ^\n  syntax error at column 8: unknown word `frob`\n",
+                           ">       ^\n  syntax error at column 5: unknown word `dup`\n> \n"));
+    }
+
+    #[test]
+    fn test_repl_check() {
+        let mut output = Vec::new();
+        let input = b"1 true\ncheck drop 2 +\ncheck +\ncheck if 1 2 then\n.s\n";
+        assert!(repl(&mut Session::new(), &input[..], &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   concat!(">   true\n",
+                           ">   ( num any -- num )\n",
+                           ">         ^\n  check failed at column 7: `+` needs num but would get bool\n",
+                           ">   ok\n",
+                           ">   1: 1\n  0: true\n  (undo 1/100, redo 0)\n> \n"));
+    }
+
+    #[test]
+    fn test_repl_trace() {
+        let mut output = Vec::new();
+        let input = b"trace on\n3 : sq dup * ; sq\ntrace off\n1 +\ntrace\n";
+        assert!(repl(&mut Session::new(), &input[..], &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   concat!("> >   3  [] -> [3]\n",
+                           "    dup  [3] -> [3 3]\n",
+                           "    *  [3 3] -> [9]\n",
+                           "  sq  [3] -> [9]\n",
+                           "  9\n",
+                           "> >   10\n",
+                           ">   ^\n  syntax error at column 1: expected `trace on|off`\n> \n"));
+    }
+
+    #[test]
+    fn test_repl_step() {
+        let mut output = Vec::new();
+        let input = b": sq dup * ;\nbreak sq\nbreak\n2 sq 1 +\nstack\nbogus\n\ncontinue\nstep 5 6\nabort\n.s\nunbreak sq\nunbreak sq\nbreak nope\n";
+        assert!(repl(&mut Session::new(), &input[..], &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   concat!("> > >   sq\n",
+                           ">   at `sq` with stack [2]\n",
+                           "debug>   [2]\n",
+                           "debug>   unknown debugger command `bogus`; try step, continue, stack, abort\n",
+                           "debug>   at `dup` with stack [2]\n",
+                           "debug>   5\n",
+                           ">   at `5` with stack [5]\n",
+                           "debug>   stopped in the debugger\n",
+                           ">   0: 5\n  (undo 1/100, redo 0)\n",
+                           "> >           ^\n  syntax error at column 9: no breakpoint at `sq`\n",
+                           ">         ^\n  syntax error at column 7: unknown word `nope`\n> \n"));
+    }
+
+    #[test]
+    fn test_repl_infix() {
+        let mut output = Vec::new();
+        let input = b"-2 !x\n=(3 + 4) * -x == 14\nshow-rpn (3 + 4) * -x == 7\ninfix on\n2 ** 3 ** 2\n\n1 +\n1 1 =\ninfix off\n= 1\n";
+        assert!(repl(&mut Session::new(), &input[..], &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   concat!("> >   true\n",
+                           ">   3 4 + @x ~ * 7 =\n",
+                           "> infix>   512\ninfix>   512\n",
+                           "infix>           ^\n  syntax error at column 4: expected a value at the end\n",
+                           "infix>            ^\n  syntax error at column 5: unexpected `=`\n",
+                           "infix> >   1\n> \n"));
+    }
+
+    #[test]
+    fn test_evaluate_line_infix() {
+        let mut session = Session::new();
+        assert!(evaluate_line(&mut session, "5 !n").is_ok());
+        assert!(evaluate_line(&mut session, "=n * (n - 1) / 2").is_ok());
+        assert_eq!(session.stack.values(), &[Elt::Int(10)]);
+        if let Err(Error::Syntax(ref err)) = evaluate_line(&mut session, "  =1 + )") {
+            assert_eq!(err.column, 7);
+        } else {
+            panic!();
+        }
+        assert_eq!(session.stack.values(), &[Elt::Int(10)]);
+        // Errors in running the translation point into the expression, not
+        // into its RPN.
+        session.ops.remove("~");
+        if let Err(Error::Syntax(ref err)) = evaluate_line(&mut session, "=(n + 2)*-n") {
+            assert_eq!(err.column, 9);
+            assert_eq!(err.message, "unknown word `~`");
+        } else {
+            panic!();
+        }
+        let mut output = Vec::new();
+        assert!(repl(&mut session, &b"infix on\n(n + 2)*-n\n"[..], &mut output).is_ok());
+        let expected = format!("infix>{}^\n  syntax error at column 9: unknown word `~`\n", " ".repeat(16));
+        assert!(String::from_utf8(output).unwrap().contains(&expected));
+    }
+
+    #[test]
+    fn test_evaluate_line_nesting() {
+        let mut session = Session::new();
+        let deepest = "[ ".repeat(MAX_NESTING) + &"] ".repeat(MAX_NESTING) + "drop " +
+                      &"{ ".repeat(MAX_NESTING) + &"} ".repeat(MAX_NESTING) + "drop";
+        assert!(evaluate_line(&mut session, &deepest).is_ok());
+        // Too deep is an error at the first construct past the limit, not
+        // a stack overflow.
+        for open in &["[", "{", "if", "begin", "do"] {
+            let line = format!("1 {}", format!("{} ", open).repeat(100_000));
+            if let Err(Error::Syntax(ref err)) = evaluate_line(&mut session, &line) {
+                assert_eq!(err.column, 2 + (open.len() + 1) * MAX_NESTING);
+                assert_eq!(err.message, format!("`{}` nested more than {} deep", open, MAX_NESTING));
+            } else { panic!("{}", open); }
+        }
+        assert!(session.stack.values().is_empty());
+    }
+
+    #[test]
+    fn test_evaluate_line_strings() {
+        let mut session = Session::new();
+        let s = "\"hello, \\\"big\\\"  world\" \"!\" concat length".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.values(), &[Elt::Int(20)]);
+        for &(s, column, message) in &[
+            ("1 \"abc", 2, "unterminated string"),
+            ("\"a\\qb\"", 0, "bad escape in string \"a\\qb\""),
+            ("{ 1 dup }", 4, "`dup` can't go in a list"),
+            ("{ 1 { 2 }", 0, "`{` without `}`"),
+            ("[ 1 ] ]", 6, "unexpected `]`"),
+        ] {
+            if let Err(Error::Syntax(ref err)) = evaluate_line(&mut session, s) {
+                assert_eq!((err.column, err.message.as_str()), (column, message));
+            } else {
+                panic!("{}", s);
+            }
+        }
+    }
+
+    #[test]
+    fn test_evaluate_line_quotes() {
+        let mut session = Session::new();
+        let s = "{ 1 2 3 4 } [ dup * ] map { 1 2 3 4 } [ 2 % 0 = ] filter { 1 2 3 4 } 0 [ + ] fold".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        assert_eq!(session.stack.values(),
+                   &[Elt::List(vec![Elt::Int(1), Elt::Int(4), Elt::Int(9), Elt::Int(16)]),
+                     Elt::List(vec![Elt::Int(2), Elt::Int(4)]), Elt::Int(10)]);
+
+        assert!(evaluate_line(&mut session, "clear { [ 1 ] } 0 index call").is_ok());
+        assert_eq!(session.stack.values(), &[Elt::Int(1)]);
+
+        for s in &["{ 1 } [ drop ] map", "{ 1 } [ 1 ] filter", "{ 1 } 0 [ 1 ] fold"] {
+            if let Err(Error::Arity(_)) = evaluate_line(&mut session, s) { } else { panic!("{}", s); }
+            assert_eq!(session.stack.values(), &[Elt::Int(1)]);
+        }
+        if let Err(Error::Type(_)) = evaluate_line(&mut session, "{ 1 } [ 2 ] drop 3 map") { } else { panic!(); }
+        if let Err(Error::Type(_)) = evaluate_line(&mut session, "{ 1 } [ ] filter") { } else { panic!(); }
+        if let Err(Error::Recursion(_)) = evaluate_line(&mut session, "[ dup call ] dup call") { } else { panic!(); }
+        assert_eq!(session.stack.values(), &[Elt::Int(1)]);
+    }
+
+    #[test]
+    fn test_session_save_load_strings() {
+        let mut session = Session::new();
+        let s = ": sq dup * ; \"a  \\\"b\\\"\" { 1.5 \"c d\" [ sq ] { } } [ 1 + ] !q".to_string();
+        assert!(evaluate_line(&mut session, &s).is_ok());
+        let mut saved = Vec::new();
+        assert!(session.save(&mut saved).is_ok());
+        let text = String::from_utf8(saved.clone()).unwrap();
+        assert!(text.contains("stack str \"a  \\\"b\\\"\"\n"));
+        assert!(text.contains("stack list { 1.5 \"c d\" [ sq ] { } }\n"));
+        assert!(text.contains("var q quote [ 1 + ]\n"));
+
+        let mut loaded = Session::new();
+        loaded.load(&saved[..]).unwrap();
+        assert_eq!(loaded.stack.values(), session.stack.values());
+        assert!(evaluate_line(&mut loaded, "2 index 3 <-> call @q call").is_ok());
+        assert_eq!(loaded.stack.pop().unwrap(), Elt::Int(10));
+    }
+
+    #[test]
+    fn test_session_save_load_non_finite() {
+        let mut session = Session::new();
+        assert!(evaluate_line(&mut session, "{ 1e999 -1e999 } { 1e999 } [ dup - ] map concat { { 1e999 } }").is_ok());
+        let mut saved = Vec::new();
+        assert!(session.save(&mut saved).is_ok());
+        let text = String::from_utf8(saved.clone()).unwrap();
+        assert!(text.contains("stack list { inf -inf NaN }\n"));
+        assert!(text.contains("stack list { { inf } }\n"));
+
+        let mut loaded = Session::new();
+        loaded.load(&saved[..]).unwrap();
+        assert_eq!(loaded.stack.to_string(), session.stack.to_string());
+        assert_eq!(loaded.stack.values()[1], Elt::List(vec![Elt::List(vec![Elt::Float(f64::INFINITY)])]));
     }
 }
@@ -0,0 +1,232 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// An exact fraction `num / den`, always kept in lowest terms with a positive
+/// denominator. Arithmetic is checked: operations return `None` instead of
+/// overflowing.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Rational {
+    num: i64,
+    den: i64,
+}
+
+fn gcd(mut a: i128, mut b: i128) -> i128 {
+    while b != 0 {
+        let t = a % b;
+        a = b;
+        b = t;
+    }
+    a.abs()
+}
+
+impl Rational {
+    /// Creates the fraction `num / den`, or `None` if `den` is zero.
+    pub fn new(num: i64, den: i64) -> Option<Rational> {
+        Rational::reduce(num as i128, den as i128)
+    }
+
+    /// Creates a fraction equal to an integer.
+    pub fn from_int(n: i64) -> Rational {
+        Rational { num: n, den: 1 }
+    }
+
+    /// Finds the simplest fraction that converts back to exactly `x`, or
+    /// `None` if `x` is not finite or no such fraction fits in an `i64`.
+    pub fn from_f64(x: f64) -> Option<Rational> {
+        if !x.is_finite() {
+            return None;
+        }
+        // Walk the continued fraction expansion of |x| until a convergent
+        // h/k reproduces it.
+        let (mut h0, mut h1) = (0i64, 1i64);
+        let (mut k0, mut k1) = (1i64, 0i64);
+        let mut y = x.abs();
+        for _ in 0..64 {
+            let a = y.floor();
+            if a >= i64::MAX as f64 {
+                return None;
+            }
+            let a = a as i64;
+            let h2 = a.checked_mul(h1).and_then(|n| n.checked_add(h0))?;
+            let k2 = a.checked_mul(k1).and_then(|n| n.checked_add(k0))?;
+            h0 = h1;
+            h1 = h2;
+            k0 = k1;
+            k1 = k2;
+            let frac = y - a as f64;
+            if h1 as f64 / k1 as f64 == x.abs() || frac == 0.0 {
+                break;
+            }
+            y = 1.0 / frac;
+        }
+        let num = if x < 0.0 { -h1 } else { h1 };
+        Rational::new(num, k1)
+    }
+
+    /// Brings `num / den` into lowest terms, or returns `None` if `den` is zero
+    /// or the result does not fit.
+    fn reduce(num: i128, den: i128) -> Option<Rational> {
+        if den == 0 {
+            return None;
+        }
+        let g = gcd(num, den);
+        let (mut num, mut den) = (num / g, den / g);
+        if den < 0 {
+            num = -num;
+            den = -den;
+        }
+        if num < i64::MIN as i128 || num > i64::MAX as i128 || den > i64::MAX as i128 {
+            return None;
+        }
+        Some(Rational { num: num as i64, den: den as i64 })
+    }
+
+    /// The numerator, carrying the sign of the fraction.
+    pub fn numer(&self) -> i64 {
+        self.num
+    }
+
+    /// The denominator, which is always positive.
+    pub fn denom(&self) -> i64 {
+        self.den
+    }
+
+    /// Rounds towards zero.
+    pub fn trunc(&self) -> i64 {
+        self.num / self.den
+    }
+
+    /// Converts to the nearest floating-point number.
+    pub fn to_f64(self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+
+    pub fn checked_add(self, other: Rational) -> Option<Rational> {
+        let (a, b, c, d) = self.wide(other);
+        Rational::reduce(a.checked_mul(d)?.checked_add(c.checked_mul(b)?)?, b.checked_mul(d)?)
+    }
+
+    pub fn checked_sub(self, other: Rational) -> Option<Rational> {
+        self.checked_add(other.checked_neg()?)
+    }
+
+    pub fn checked_mul(self, other: Rational) -> Option<Rational> {
+        let (a, b, c, d) = self.wide(other);
+        Rational::reduce(a.checked_mul(c)?, b.checked_mul(d)?)
+    }
+
+    /// Divides, returning `None` on overflow or division by zero.
+    pub fn checked_div(self, other: Rational) -> Option<Rational> {
+        let (a, b, c, d) = self.wide(other);
+        Rational::reduce(a.checked_mul(d)?, b.checked_mul(c)?)
+    }
+
+    /// The remainder of a division whose quotient is rounded towards zero,
+    /// like `%` on integers.
+    pub fn checked_rem(self, other: Rational) -> Option<Rational> {
+        let (a, b, c, d) = self.wide(other);
+        if c == 0 {
+            return None;
+        }
+        let q = a.checked_mul(d)? / b.checked_mul(c)?;
+        let num = a.checked_mul(d)?.checked_sub(q.checked_mul(c)?.checked_mul(b)?)?;
+        Rational::reduce(num, b.checked_mul(d)?)
+    }
+
+    pub fn checked_neg(self) -> Option<Rational> {
+        Some(Rational { num: self.num.checked_neg()?, den: self.den })
+    }
+
+    /// Both fractions' parts, widened for intermediate arithmetic.
+    fn wide(self, other: Rational) -> (i128, i128, i128, i128) {
+        (self.num as i128, self.den as i128, other.num as i128, other.den as i128)
+    }
+}
+
+impl Ord for Rational {
+    fn cmp(&self, other: &Rational) -> Ordering {
+        let (a, b, c, d) = self.wide(*other);
+        (a * d).cmp(&(c * b))
+    }
+}
+
+impl PartialOrd for Rational {
+    fn partial_cmp(&self, other: &Rational) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Rational {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.num, self.den)
+    }
+}
+
+impl FromStr for Rational {
+    type Err = ();
+
+    /// Parses `num/den`, e.g. `7/3` or `-1/2`.
+    fn from_str(s: &str) -> Result<Rational, ()> {
+        let mut parts = s.splitn(2, '/');
+        let num = parts.next().and_then(|n| i64::from_str(n).ok()).ok_or(())?;
+        let den = parts.next().and_then(|d| i64::from_str(d).ok()).ok_or(())?;
+        Rational::new(num, den).ok_or(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_reduces() {
+        let r = Rational::new(6, -4).unwrap();
+        assert_eq!(r.numer(), -3);
+        assert_eq!(r.denom(), 2);
+        assert!(Rational::new(1, 0).is_none());
+    }
+
+    #[test]
+    fn test_arith() {
+        let a = Rational::new(1, 2).unwrap();
+        let b = Rational::new(1, 3).unwrap();
+        assert_eq!(a.checked_add(b).unwrap(), Rational::new(5, 6).unwrap());
+        assert_eq!(a.checked_sub(b).unwrap(), Rational::new(1, 6).unwrap());
+        assert_eq!(a.checked_mul(b).unwrap(), Rational::new(1, 6).unwrap());
+        assert_eq!(a.checked_div(b).unwrap(), Rational::new(3, 2).unwrap());
+        assert_eq!(a.checked_rem(b).unwrap(), Rational::new(1, 6).unwrap());
+        assert!(a.checked_div(Rational::from_int(0)).is_none());
+    }
+
+    #[test]
+    fn test_overflow() {
+        let big = Rational::from_int(i64::MAX);
+        assert!(big.checked_add(Rational::from_int(1)).is_none());
+        assert!(big.checked_mul(big).is_none());
+    }
+
+    #[test]
+    fn test_cmp() {
+        assert!(Rational::new(1, 3).unwrap() < Rational::new(1, 2).unwrap());
+        assert!(Rational::new(-1, 2).unwrap() < Rational::from_int(0));
+    }
+
+    #[test]
+    fn test_from_f64() {
+        assert_eq!(Rational::from_f64(0.1).unwrap(), Rational::new(1, 10).unwrap());
+        assert_eq!(Rational::from_f64(-2.5).unwrap(), Rational::new(-5, 2).unwrap());
+        assert_eq!(Rational::from_f64(3.0).unwrap(), Rational::from_int(3));
+        assert!(Rational::from_f64(1e300).is_none());
+        assert!(Rational::from_f64(f64::NAN).is_none());
+    }
+
+    #[test]
+    fn test_parse_display() {
+        let r: Rational = "7/3".parse().unwrap();
+        assert_eq!(r.to_string(), "7/3");
+        assert!("7/0".parse::<Rational>().is_err());
+        assert!("7".parse::<Rational>().is_err());
+        assert!("a/3".parse::<Rational>().is_err());
+    }
+}
@@ -1,56 +1,667 @@
-use std::result;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
 use std::io;
-use rand::{thread_rng,Rng};
+use std::rc::Rc;
+use std::result;
+use std::str::FromStr;
+use rand::{weak_rng, Rng, SeedableRng, XorShiftRng};
 
-#[derive(Eq, PartialEq, Ord, PartialOrd, Debug)]
-/// An element of the stack. May be either integer or boolean.
+use bigint::BigInt;
+use bytecode::Word;
+use rational::Rational;
+
+#[derive(Clone, PartialEq, PartialOrd, Debug)]
+/// An element of the stack. May be an integer, boolean, floating-point
+/// number, exact fraction, string, list, or quotation.
 pub enum Elt {
     Int(i32),
     Bool(bool),
+    Float(f64),
+    Rational(Rational),
+    /// An integer too large for `Int`. Integers that fit are always stored
+    /// as `Int` instead.
+    Big(BigInt),
+    Str(String),
+    List(Vec<Elt>),
+    /// A block of code, run by `call`, `map`, `filter` and `fold`.
+    Quote(Rc<Word>),
+}
+
+/// The largest result, in bits, that `Op::Pow` will compute.
+const MAX_POW_BITS: u64 = 1 << 20;
+
+impl Elt {
+    /// Stores an integer as `Int` if it fits, or `Big` otherwise.
+    pub fn from_big(n: BigInt) -> Elt {
+        match n.to_i32() {
+            Some(n) => Elt::Int(n),
+            None => Elt::Big(n),
+        }
+    }
+
+    /// The value as a floating-point number, if it is numeric.
+    fn to_f64(&self) -> Option<f64> {
+        match *self {
+            Elt::Int(n) => Some(n as f64),
+            Elt::Float(x) => Some(x),
+            Elt::Rational(r) => Some(r.to_f64()),
+            Elt::Big(ref n) => Some(n.to_f64()),
+            _ => None,
+        }
+    }
+
+    /// The value as an exact fraction, if it is an integer or a fraction.
+    fn to_rational(&self) -> Option<Rational> {
+        match *self {
+            Elt::Int(n) => Some(Rational::from_int(n as i64)),
+            Elt::Rational(r) => Some(r),
+            Elt::Big(ref n) => n.to_i64().map(Rational::from_int),
+            _ => None,
+        }
+    }
+
+    /// The value as a big integer, if it is an integer.
+    fn to_big(&self) -> Option<BigInt> {
+        match *self {
+            Elt::Int(n) => Some(BigInt::from_i64(n as i64)),
+            Elt::Big(ref n) => Some(n.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Elt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Elt::Int(n) => write!(f, "{}", n),
+            Elt::Bool(b) => write!(f, "{}", b),
+            // Debug formatting keeps the decimal point, so that e.g. 2.0 is
+            // not mistaken for an integer.
+            Elt::Float(x) => write!(f, "{:?}", x),
+            Elt::Rational(r) => write!(f, "{}", r),
+            Elt::Big(ref n) => write!(f, "{}", n),
+            Elt::Str(ref s) => {
+                f.write_str("\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => f.write_str("\\\"")?,
+                        '\\' => f.write_str("\\\\")?,
+                        '\n' => f.write_str("\\n")?,
+                        '\t' => f.write_str("\\t")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                f.write_str("\"")
+            },
+            Elt::List(ref elts) => {
+                f.write_str("{")?;
+                for elt in elts {
+                    write!(f, " {}", elt)?;
+                }
+                f.write_str(" }")
+            },
+            Elt::Quote(ref word) => {
+                f.write_str("[")?;
+                for node in word.body.iter() {
+                    write!(f, " {}", node)?;
+                }
+                f.write_str(" ]")
+            },
+        }
+    }
 }
 
 #[derive(Debug)]
 /// An RPN calculator error.
 pub enum Error {
     /// Tried to pop from an empty stack.
-    Underflow,
+    Underflow(Fault),
     /// Tried to operate on invalid types (e.g. 4 + true)
-    Type,
+    Type(Fault),
     /// Unable to parse the input.
-    Syntax,
+    Syntax(SyntaxError),
     /// Some IO error occurred.
     IO(io::Error),
     /// The user quit the program (with `quit`).
     Quit,
+    /// A definition refers to a word that does not exist.
+    Undefined(String),
+    /// A user-defined word recursed past the expansion limit.
+    Recursion(String),
+    /// Tried to divide (or take the remainder) by zero.
+    DivideByZero,
+    /// An integer result did not fit: in `Arith::Checked` mode, or converting
+    /// to an integer.
+    Overflow,
+    /// An argument was of the right type but out of range (e.g. 0 #).
+    Range(Fault),
+    /// A quotation given to `map`, `filter` or `fold` did not leave exactly
+    /// one value.
+    Arity(Fault),
+    /// Tried to read or delete a variable that has no value.
+    Unbound(String),
+    /// A saved session could not be read back: the line number, from 1, and
+    /// what was wrong with it.
+    Corrupt(usize, String),
+    /// Checking a line found a token that would fail when run.
+    Check(SyntaxError),
+    /// The line was stopped from the debugger.
+    Stopped,
 }
 
-#[derive(Debug)]
+#[derive(Clone, PartialEq, Debug, Default)]
+/// The operator that failed, and the stack it saw when it did.
+pub struct Fault {
+    pub op: String,
+    /// The stack contents, bottom first.
+    pub stack: Vec<Elt>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+/// Where and why a line could not be parsed.
+pub struct SyntaxError {
+    /// The offending token.
+    pub token: String,
+    /// The column of the line, in characters from 0, where the token starts.
+    pub column: usize,
+    pub message: String,
+}
+
+impl Error {
+    /// A short name for the kind of error, e.g. `underflow`, for programs
+    /// that read errors rather than people.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Error::Underflow(_) => "underflow",
+            Error::Type(_) => "type",
+            Error::Syntax(_) => "syntax",
+            Error::IO(_) => "io",
+            Error::Quit => "quit",
+            Error::Undefined(_) => "undefined",
+            Error::Recursion(_) => "recursion",
+            Error::DivideByZero => "divide-by-zero",
+            Error::Overflow => "overflow",
+            Error::Range(_) => "range",
+            Error::Arity(_) => "arity",
+            Error::Unbound(_) => "unbound",
+            Error::Corrupt(..) => "corrupt",
+            Error::Check(_) => "check",
+            Error::Stopped => "stopped",
+        }
+    }
+
+    /// Blames an underflow, type, range or arity error on `fault`, leaving
+    /// other errors as they are.
+    pub fn at(self, fault: Fault) -> Error {
+        match self {
+            Error::Underflow(_) => Error::Underflow(fault),
+            Error::Type(_) => Error::Type(fault),
+            Error::Range(_) => Error::Range(fault),
+            Error::Arity(_) => Error::Arity(fault),
+            err => err,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IO(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Underflow(ref fault) => write!(f, "stack underflow in {}", fault),
+            Error::Type(ref fault) => write!(f, "type error in {}", fault),
+            Error::Syntax(ref err) => write!(f, "syntax error at column {}: {}", err.column + 1, err.message),
+            Error::IO(ref err) => write!(f, "I/O error: {}", err),
+            Error::Quit => f.write_str("quit"),
+            Error::Undefined(ref word) => write!(f, "undefined word `{}`", word),
+            Error::Recursion(ref word) => write!(f, "`{}` recursed too deeply", word),
+            Error::DivideByZero => f.write_str("division by zero"),
+            Error::Overflow => f.write_str("integer overflow"),
+            Error::Range(ref fault) => write!(f, "argument out of range in {}", fault),
+            Error::Arity(ref fault) => write!(f, "quotation must leave one value in {}", fault),
+            Error::Unbound(ref name) => write!(f, "unbound variable `{}`", name),
+            Error::Corrupt(line, ref message) => write!(f, "bad session file, line {}: {}", line, message),
+            Error::Check(ref err) => write!(f, "check failed at column {}: {}", err.column + 1, err.message),
+            Error::Stopped => f.write_str("stopped in the debugger"),
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}` with stack [", self.op)?;
+        for (i, elt) in self.stack.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}", elt)?;
+        }
+        f.write_str("]")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 /// Types of RPN calculator operations.
 pub enum Op {
     /// Adds two numbers: pop x, pop y, push x + y.
     Add,
+    /// Subtracts two numbers: pop x, pop y, push y - x.
+    Sub,
+    /// Multiplies two numbers: pop x, pop y, push x * y.
+    Mul,
+    /// Divides two numbers: pop x, pop y, push y / x.
+    Div,
+    /// Takes the remainder of a division: pop x, pop y, push y % x.
+    Rem,
+    /// Raises a number to a power: pop x, pop y, push y ** x.
+    Pow,
     /// Checks equality of two values: pop x, pop y, push x == y.
     Eq,
+    /// Checks inequality of two values: pop x, pop y, push x != y.
+    Ne,
+    /// Compares two numbers: pop x, pop y, push y < x.
+    Lt,
+    /// Compares two numbers: pop x, pop y, push y <= x.
+    Le,
+    /// Compares two numbers: pop x, pop y, push y > x.
+    Gt,
+    /// Compares two numbers: pop x, pop y, push y >= x.
+    Ge,
+    /// Logical conjunction of two booleans: pop x, pop y, push y && x.
+    And,
+    /// Logical disjunction of two booleans: pop x, pop y, push y || x.
+    Or,
+    /// Logical negation of a boolean: pop x, push !x.
+    Not,
+    /// Converts a number to an integer, rounding towards zero.
+    ToInt,
+    /// Converts a number to floating point.
+    ToFloat,
+    /// Converts a number to an exact fraction. Floats become the simplest
+    /// fraction that converts back to the same float.
+    ToRational,
     /// Negates a value: pop x, push ~x.
     Neg,
     /// Swaps two values: pop x, pop y, push x, push y.
     Swap,
+    /// Duplicates a value: pop x, push x, push x.
+    Dup,
+    /// Discards a value: pop x.
+    Drop,
+    /// Copies the second value: pop x, pop y, push y, push x, push y.
+    Over,
+    /// Rotates the third value to the top: pop x, pop y, pop z, push y,
+    /// push x, push z.
+    Rot,
+    /// Rotates the top value to third place: pop x, pop y, pop z, push x,
+    /// push z, push y.
+    RotBack,
+    /// Discards the second value: pop x, pop y, push x.
+    Nip,
+    /// Copies the top value below the second: pop x, pop y, push x, push y,
+    /// push x.
+    Tuck,
+    /// Copies a value from deeper in the stack: pop n, push the value n
+    /// places below the top. `0 pick` is `dup`.
+    Pick,
+    /// Moves a value from deeper in the stack to the top: pop n, then take
+    /// out the value n places below the top and push it. `1 roll` is `<->`.
+    Roll,
+    /// Discards every value on the stack.
+    Clear,
+    /// Pushes the number of values on the stack.
+    Depth,
     /// Computes a random number: pop x, push random number in [0, x).
     Rand,
+    /// Restarts the random number generator: pop x, seed it with x.
+    Seed,
+    /// Joins two strings or two lists: pop x, pop y, push y followed by x.
+    Concat,
+    /// Counts the characters of a string or the values of a list: pop x,
+    /// push its length.
+    Length,
+    /// Takes one character of a string or one value of a list: pop n, pop x,
+    /// push x's nth, from 0.
+    Index,
+    /// Runs a quotation: pop q, run q.
+    Call,
+    /// Runs a quotation on each value of a list, collecting the values it
+    /// leaves: pop q, pop list, push the new list.
+    Map,
+    /// Keeps the values of a list for which a quotation leaves true: pop q,
+    /// pop list, push the values kept.
+    Filter,
+    /// Combines the values of a list with a quotation, starting from an
+    /// initial value: pop q, pop init, pop list, then push init and, for
+    /// each value, push it and run q.
+    Fold,
     /// Quit the calculator.
     Quit,
 }
 
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+/// How integer operations behave when their result does not fit in an `i32`.
+pub enum Arith {
+    /// Fail with `Error::Overflow`, leaving the operands on the stack.
+    Checked,
+    /// Wrap around at the boundaries of `i32`.
+    Wrapping,
+    /// Clamp to `i32::MIN` or `i32::MAX`.
+    Saturating,
+    /// Switch to arbitrary-precision integers. This is the default.
+    #[default]
+    Bignum,
+}
+
+impl Arith {
+    /// Picks the result appropriate for this mode, given the checked,
+    /// wrapping and saturating versions of an operation.
+    fn pick(self, checked: Option<i32>, wrapping: i32, saturating: i32) -> Result<i32> {
+        match self {
+            Arith::Checked | Arith::Bignum => checked.ok_or(Error::Overflow),
+            Arith::Wrapping => Ok(wrapping),
+            Arith::Saturating => Ok(saturating),
+        }
+    }
+}
+
+impl fmt::Display for Arith {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Arith::Checked => "checked",
+            Arith::Wrapping => "wrapping",
+            Arith::Saturating => "saturating",
+            Arith::Bignum => "bignum",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Arith {
+    type Err = ();
+
+    fn from_str(s: &str) -> result::Result<Arith, ()> {
+        match s {
+            "checked" => Ok(Arith::Checked),
+            "wrapping" => Ok(Arith::Wrapping),
+            "saturating" => Ok(Arith::Saturating),
+            "bignum" => Ok(Arith::Bignum),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A saved copy of a stack's state, which can later be restored.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    values: Vec<Elt>,
+    arith: Arith,
+    rng: XorShiftRng,
+}
+
+/// A bounded history of stack states, which `undo` and `redo` step through.
+#[derive(Debug)]
+pub struct Undo {
+    /// States to go back to, oldest first.
+    past: VecDeque<Snapshot>,
+    /// States undone, most recently undone last.
+    future: Vec<Snapshot>,
+    /// How many states to keep in `past`.
+    depth: usize,
+}
+
+impl Undo {
+    /// Creates an empty history that keeps up to `depth` states.
+    pub fn new(depth: usize) -> Undo {
+        Undo { past: VecDeque::new(), future: Vec::new(), depth }
+    }
+
+    /// How many states are kept.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Changes how many states are kept, forgetting the oldest if needed.
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        while self.past.len() > depth {
+            self.past.pop_front();
+        }
+    }
+
+    /// How many steps can be undone and redone.
+    pub fn position(&self) -> (usize, usize) {
+        (self.past.len(), self.future.len())
+    }
+
+    /// Remembers `before`, the state `stack` was in before a change, unless
+    /// nothing changed. Anything undone can no longer be redone.
+    pub fn record(&mut self, before: Snapshot, stack: &Stack) {
+        if before.values == stack.values && before.arith == stack.arith {
+            return;
+        }
+        self.future.clear();
+        if self.depth == 0 {
+            return;
+        }
+        if self.past.len() == self.depth {
+            self.past.pop_front();
+        }
+        self.past.push_back(before);
+    }
+
+    /// Puts `stack` back into the state before the last change. Returns
+    /// false if there is nothing to undo.
+    pub fn undo(&mut self, stack: &mut Stack) -> bool {
+        match self.past.pop_back() {
+            Some(snapshot) => {
+                self.future.push(stack.snapshot());
+                stack.restore(snapshot);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Reapplies the last change undone. Returns false if there is nothing
+    /// to redo.
+    pub fn redo(&mut self, stack: &mut Stack) -> bool {
+        match self.future.pop() {
+            Some(snapshot) => {
+                self.past.push_back(stack.snapshot());
+                stack.restore(snapshot);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
 pub struct Stack {
     values: Vec<Elt>,
+    arith: Arith,
+    /// The source of `Op::Rand`'s numbers.
+    rng: XorShiftRng,
+}
+
+impl fmt::Display for Stack {
+    /// Writes the values bottom first, e.g. `[1 2 true]`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("[")?;
+        for (i, elt) in self.values.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}", elt)?;
+        }
+        f.write_str("]")
+    }
+}
+
+/// Watches evaluation one token at a time, e.g. to trace it or to stop at
+/// breakpoints. `depth` counts the user-defined words the token is running
+/// in, and an error from either hook stops evaluation with that error.
+pub trait Observer {
+    /// Called before `token` runs, with the stack it will see.
+    fn before(&mut self, _token: &str, _depth: usize, _stack: &Stack) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called after `token` has run, with the stack it left.
+    fn after(&mut self, _token: &str, _depth: usize, _stack: &Stack) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Watches nothing.
+impl Observer for () {}
+
+/// Named variables and numbered registers (`0` to `9`), which hold values
+/// off the stack.
+#[derive(Clone, Debug, Default)]
+pub struct Vars {
+    values: BTreeMap<String, Elt>,
+}
+
+impl Vars {
+    /// Creates an environment with no variables.
+    pub fn new() -> Vars {
+        Vars { values: BTreeMap::new() }
+    }
+
+    /// Binds `name` to `elt`, replacing any previous value.
+    pub fn store(&mut self, name: &str, elt: Elt) {
+        self.values.insert(name.to_string(), elt);
+    }
+
+    /// Returns a copy of the value bound to `name`.
+    pub fn fetch(&self, name: &str) -> Result<Elt> {
+        self.values.get(name).cloned().ok_or_else(|| Error::Unbound(name.to_string()))
+    }
+
+    /// Unbinds `name`, returning the value it had.
+    pub fn delete(&mut self, name: &str) -> Result<Elt> {
+        self.values.remove(name).ok_or_else(|| Error::Unbound(name.to_string()))
+    }
+
+    /// The bound variables and their values, in order of name.
+    pub fn list(&self) -> impl Iterator<Item = (&str, &Elt)> {
+        self.values.iter().map(|(name, elt)| (name.as_str(), elt))
+    }
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// The token for each operator.
+const TOKENS: &[(&str, Op)] = &[
+    ("+", Op::Add), ("-", Op::Sub), ("*", Op::Mul), ("/", Op::Div), ("%", Op::Rem),
+    ("**", Op::Pow), ("~", Op::Neg),
+    ("=", Op::Eq), ("!=", Op::Ne), ("<", Op::Lt), ("<=", Op::Le), (">", Op::Gt), (">=", Op::Ge),
+    ("and", Op::And), ("or", Op::Or), ("not", Op::Not),
+    (">int", Op::ToInt), (">float", Op::ToFloat), (">rat", Op::ToRational),
+    ("#", Op::Rand), ("seed", Op::Seed), ("quit", Op::Quit),
+    ("<->", Op::Swap), ("dup", Op::Dup), ("drop", Op::Drop), ("over", Op::Over),
+    ("rot", Op::Rot), ("-rot", Op::RotBack), ("nip", Op::Nip), ("tuck", Op::Tuck),
+    ("pick", Op::Pick), ("roll", Op::Roll), ("clear", Op::Clear), ("depth", Op::Depth),
+    ("concat", Op::Concat), ("length", Op::Length), ("index", Op::Index),
+    ("call", Op::Call), ("map", Op::Map), ("filter", Op::Filter), ("fold", Op::Fold),
+];
+
+impl Op {
+    /// Whether the operator runs quotations, which `Stack::eval` can't do on
+    /// its own.
+    pub fn runs_quotes(self) -> bool {
+        matches!(self, Op::Call | Op::Map | Op::Filter | Op::Fold)
+    }
+
+    /// How many values the operator takes. `pick` and `roll` take more below
+    /// their argument, depending on what it is.
+    pub fn arity(self) -> usize {
+        match self {
+            Op::Swap | Op::Over | Op::Nip | Op::Tuck | Op::Add | Op::Sub | Op::Mul | Op::Div |
+            Op::Rem | Op::Pow | Op::Eq | Op::Ne | Op::Lt | Op::Le | Op::Gt | Op::Ge | Op::And |
+            Op::Or | Op::Concat | Op::Index | Op::Map | Op::Filter => 2,
+            Op::Rot | Op::RotBack | Op::Fold => 3,
+            Op::Clear | Op::Depth | Op::Quit => 0,
+            _ => 1,
+        }
+    }
+
+    /// Finds the operator written as `token`, if any.
+    pub fn from_token(token: &str) -> Option<Op> {
+        TOKENS.iter().find(|&&(t, _)| t == token).map(|&(_, op)| op)
+    }
+
+    /// The token that writes this operator.
+    pub fn token(self) -> &'static str {
+        TOKENS.iter().find(|&&(_, op)| op == self).map(|&(t, _)| t).expect("every op has a token")
+    }
+
+    /// The tokens of every operator.
+    pub fn tokens() -> impl Iterator<Item = &'static str> {
+        TOKENS.iter().map(|&(t, _)| t)
+    }
+}
+
+/// Expands a seed into the state of an `XorShiftRng`, which must not be all
+/// zeros.
+fn rng_from_seed(seed: u64) -> XorShiftRng {
+    XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32, 0x9e37_79b9, 0x7f4a_7c15])
+}
+
+impl Default for Stack {
+    fn default() -> Stack {
+        Stack::new()
+    }
+}
+
 impl Stack {
-    /// Creates a new Stack
+    /// Creates a new Stack, whose random numbers are seeded by the OS.
     pub fn new() -> Stack {
-        Stack { values: Vec::new() }
+        Stack { values: Vec::new(), arith: Arith::default(), rng: weak_rng() }
+    }
+
+    /// Creates a new Stack whose random numbers are always the same for the
+    /// same `seed`.
+    pub fn with_seed(seed: u64) -> Stack {
+        Stack { rng: rng_from_seed(seed), ..Stack::new() }
+    }
+
+    /// Restarts the random numbers from `seed`.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = rng_from_seed(seed);
+    }
+
+    /// Returns how integer overflow is currently handled.
+    pub fn arith(&self) -> Arith {
+        self.arith
+    }
+
+    /// Changes how integer overflow is handled from now on.
+    pub fn set_arith(&mut self, arith: Arith) {
+        self.arith = arith;
+    }
+
+    /// Saves the current state of the stack.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot { values: self.values.clone(), arith: self.arith, rng: self.rng.clone() }
+    }
+
+    /// Puts the stack back into a previously saved state.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.values = snapshot.values;
+        self.arith = snapshot.arith;
+        self.rng = snapshot.rng;
+    }
+
+    /// The values on the stack, bottom first.
+    pub fn values(&self) -> &[Elt] {
+        &self.values
     }
 
     /// Pushes a value onto the stack.
@@ -59,59 +670,513 @@ impl Stack {
         Ok(())
     }
 
+    /// Tries to look at the value on top of the stack, without popping it.
+    pub fn peek(&self) -> Result<&Elt> {
+        self.values.last().ok_or_else(|| {
+            Error::Underflow(Fault { op: "peek".to_string(), stack: Vec::new() })
+        })
+    }
+
     /// Tries to pop a value off of the stack.
     pub fn pop(&mut self) -> Result<Elt> {
-        self.values.pop().map(|e| Ok(e)).unwrap_or(Err(Error::Underflow))
+        self.values.pop().ok_or_else(|| {
+            Error::Underflow(Fault { op: "pop".to_string(), stack: Vec::new() })
+        })
     }
 
-    /// Tries to evaluate an operator using values on the stack.
+    /// Tries to evaluate an operator using values on the stack. A failing
+    /// operator leaves its operands on the stack, and type errors and
+    /// underflows report the operator and the stack it saw.
     pub fn eval(&mut self, op: Op) -> Result<()> {
+        self.apply(op).map_err(|err| err.at(self.fault(op.token())))
+    }
+
+    /// Evaluates an operator like `eval`, telling `observer` about it before
+    /// and after. `depth` counts the user-defined words it is running in.
+    pub(crate) fn eval_observed<O: Observer>(&mut self, op: Op, depth: usize, observer: &mut O) -> Result<()> {
+        observer.before(op.token(), depth, self)?;
+        self.eval(op)?;
+        observer.after(op.token(), depth, self)
+    }
+
+    /// Describes a failure of `op` on the current stack.
+    pub fn fault(&self, op: &str) -> Fault {
+        Fault { op: op.to_string(), stack: self.values.clone() }
+    }
+
+    fn apply(&mut self, op: Op) -> Result<()> {
+        let arith = self.arith;
         match op {
-            Op::Add => {
-                let elt_a = try!(self.pop());
-                let elt_b = try!(self.pop());
-                match (elt_a, elt_b) {
-                    (Elt::Int(a), Elt::Int(b)) => self.push(Elt::Int(a + b)),
-                    _ => Err(Error::Type),
-                }
+            Op::Add => self.arithmetic(
+                |y, x| arith.pick(y.checked_add(x), y.wrapping_add(x), y.saturating_add(x)),
+                |y, x| Some(y.add(x)),
+                |y, x| y.checked_add(x),
+                |y, x| y + x),
+            Op::Sub => self.arithmetic(
+                |y, x| arith.pick(y.checked_sub(x), y.wrapping_sub(x), y.saturating_sub(x)),
+                |y, x| Some(y.sub(x)),
+                |y, x| y.checked_sub(x),
+                |y, x| y - x),
+            Op::Mul => self.arithmetic(
+                |y, x| arith.pick(y.checked_mul(x), y.wrapping_mul(x), y.saturating_mul(x)),
+                |y, x| Some(y.mul(x)),
+                |y, x| y.checked_mul(x),
+                |y, x| y * x),
+            Op::Div => {
+                self.check_divisor()?;
+                self.arithmetic(
+                    |y, x| arith.pick(y.checked_div(x), y.wrapping_div(x), y.saturating_div(x)),
+                    |y, x| y.div_rem(x).map(|(q, _)| q),
+                    |y, x| y.checked_div(x),
+                    |y, x| y / x)
             },
+            Op::Rem => {
+                self.check_divisor()?;
+                self.arithmetic(
+                    |y, x| arith.pick(y.checked_rem(x), y.wrapping_rem(x), y.wrapping_rem(x)),
+                    |y, x| y.div_rem(x).map(|(_, r)| r),
+                    |y, x| y.checked_rem(x),
+                    |y, x| y % x)
+            },
+            Op::Pow => self.num_binary(|operands| pow(operands, arith)),
             Op::Eq => {
-                let elt_a = try!(self.pop());
-                let elt_b = try!(self.pop());
-                self.push(Elt::Bool(elt_a == elt_b))
+                let (elt_b, elt_a) = self.pop2()?;
+                self.push(Elt::Bool(equal(&elt_b, &elt_a)))
             },
-            Op::Neg => {
-                let elt_a = try!(self.pop());
-                match elt_a {
-                    Elt::Int(a) => self.push(Elt::Int(-a)),
-                    Elt::Bool(a) => self.push(Elt::Bool(!a)),
-                }
+            Op::Ne => {
+                let (elt_b, elt_a) = self.pop2()?;
+                self.push(Elt::Bool(!equal(&elt_b, &elt_a)))
             },
+            Op::Lt => self.compare(|ord| ord == Ordering::Less),
+            Op::Le => self.compare(|ord| ord != Ordering::Greater),
+            Op::Gt => self.compare(|ord| ord == Ordering::Greater),
+            Op::Ge => self.compare(|ord| ord != Ordering::Less),
+            Op::And => self.bool_binary(|y, x| y && x),
+            Op::Or => self.bool_binary(|y, x| y || x),
+            Op::Not => self.unary(|elt_a| {
+                match *elt_a {
+                    Elt::Bool(a) => Ok(Elt::Bool(!a)),
+                    _ => Err(Error::Type(Fault::default())),
+                }
+            }),
+            Op::Neg => self.unary(|elt_a| {
+                match *elt_a {
+                    Elt::Int(a) => {
+                        match arith.pick(a.checked_neg(), a.wrapping_neg(), a.saturating_neg()) {
+                            Err(Error::Overflow) if arith == Arith::Bignum => {
+                                Ok(Elt::from_big(BigInt::from_i64(-(a as i64))))
+                            },
+                            res => res.map(Elt::Int),
+                        }
+                    },
+                    Elt::Bool(a) => Ok(Elt::Bool(!a)),
+                    Elt::Float(a) => Ok(Elt::Float(-a)),
+                    Elt::Rational(a) => a.checked_neg().map(Elt::Rational).ok_or(Error::Overflow),
+                    Elt::Big(ref a) => Ok(Elt::from_big(a.neg())),
+                    _ => Err(Error::Type(Fault::default())),
+                }
+            }),
             Op::Swap => {
-                let elt_a = try!(self.pop());
-                let elt_b = try!(self.pop());
-                self.push(elt_a).unwrap();
-                self.push(elt_b).unwrap();
+                let (elt_b, elt_a) = self.pop2()?;
+                self.push(elt_a)?;
+                self.push(elt_b)
+            },
+            Op::Dup => {
+                let elt_a = self.nth(0)?;
+                self.push(elt_a)
+            },
+            Op::Drop => self.pop().map(|_| ()),
+            Op::Over => {
+                let elt_b = self.nth(1)?;
+                self.push(elt_b)
+            },
+            Op::Rot => self.roll(2),
+            Op::RotBack => {
+                self.nth(2)?;
+                let elt_a = self.pop()?;
+                let len = self.values.len();
+                self.values.insert(len - 2, elt_a);
+                Ok(())
+            },
+            Op::Nip => {
+                self.nth(1)?;
+                let len = self.values.len();
+                self.values.remove(len - 2);
+                Ok(())
+            },
+            Op::Tuck => {
+                let elt_a = self.nth(1).and_then(|_| self.nth(0))?;
+                let len = self.values.len();
+                self.values.insert(len - 2, elt_a);
+                Ok(())
+            },
+            Op::Pick => {
+                let n = self.index()?;
+                let elt = self.nth(n + 1)?;
+                self.values.pop();
+                self.push(elt)
+            },
+            Op::Roll => {
+                let n = self.index()?;
+                self.values.pop();
+                self.roll(n)
+            },
+            Op::Clear => {
+                self.values.clear();
                 Ok(())
             },
+            Op::Depth => {
+                let len = self.values.len();
+                if len > i32::MAX as usize {
+                    return Err(Error::Overflow);
+                }
+                self.push(Elt::Int(len as i32))
+            },
             Op::Rand => {
-                let elt_a = try!(self.pop());
-                match elt_a {
-                    Elt::Int(a) => {
-                        let mut rng = thread_rng();
-                        self.push(Elt::Int(rng.gen_range(0, a)))
+                let rng = &mut self.rng;
+                let top = self.values.last_mut().ok_or(Error::Underflow(Fault::default()))?;
+                match *top {
+                    Elt::Int(a) if a > 0 => *top = Elt::Int(rng.gen_range(0, a)),
+                    Elt::Int(_) => return Err(Error::Range(Fault::default())),
+                    _ => return Err(Error::Type(Fault::default())),
+                }
+                Ok(())
+            },
+            Op::Seed => {
+                match self.nth(0)? {
+                    Elt::Int(a) => self.set_seed(a as i64 as u64),
+                    _ => return Err(Error::Type(Fault::default())),
+                }
+                self.pop().map(|_| ())
+            },
+            Op::ToInt => self.unary(|elt_a| {
+                match *elt_a {
+                    Elt::Int(a) => Ok(Elt::Int(a)),
+                    Elt::Rational(a) => {
+                        let n = a.trunc();
+                        if n < i32::MIN as i64 || n > i32::MAX as i64 {
+                            return Err(Error::Overflow);
+                        }
+                        Ok(Elt::Int(n as i32))
+                    },
+                    Elt::Float(a) => {
+                        let n = a.trunc();
+                        if !(n >= i32::MIN as f64 && n <= i32::MAX as f64) {
+                            return Err(Error::Overflow);
+                        }
+                        Ok(Elt::Int(n as i32))
+                    },
+                    Elt::Big(_) => Err(Error::Overflow),
+                    _ => Err(Error::Type(Fault::default())),
+                }
+            }),
+            Op::ToFloat => self.unary(|elt_a| elt_a.to_f64().map(Elt::Float).ok_or(Error::Type(Fault::default()))),
+            Op::ToRational => self.unary(|elt_a| {
+                match *elt_a {
+                    Elt::Float(a) => Rational::from_f64(a).map(Elt::Rational).ok_or(Error::Overflow),
+                    Elt::Big(_) => elt_a.to_rational().map(Elt::Rational).ok_or(Error::Overflow),
+                    _ => elt_a.to_rational().map(Elt::Rational).ok_or(Error::Type(Fault::default())),
+                }
+            }),
+            Op::Concat => {
+                self.binary(|elt_b, elt_a| {
+                    match (elt_b, elt_a) {
+                        (Elt::Str(y), Elt::Str(x)) => Ok(Elt::Str(format!("{}{}", y, x))),
+                        (Elt::List(y), Elt::List(x)) => {
+                            Ok(Elt::List(y.iter().chain(x).cloned().collect()))
+                        },
+                        _ => Err(Error::Type(Fault::default())),
                     }
-                    Elt::Bool(_) => Err(Error::Type)
+                })
+            },
+            Op::Length => self.unary(|elt_a| {
+                let len = match *elt_a {
+                    Elt::Str(ref s) => s.chars().count(),
+                    Elt::List(ref elts) => elts.len(),
+                    _ => return Err(Error::Type(Fault::default())),
+                };
+                if len > i32::MAX as usize {
+                    return Err(Error::Overflow);
                 }
+                Ok(Elt::Int(len as i32))
+            }),
+            Op::Index => {
+                self.binary(|elt_b, elt_a| {
+                    let n = match *elt_a {
+                        Elt::Int(n) if n >= 0 => n as usize,
+                        Elt::Int(_) | Elt::Big(_) => return Err(Error::Range(Fault::default())),
+                        _ => return Err(Error::Type(Fault::default())),
+                    };
+                    let elt = match *elt_b {
+                        Elt::Str(ref s) => s.chars().nth(n).map(|c| Elt::Str(c.to_string())),
+                        Elt::List(ref elts) => elts.get(n).cloned(),
+                        _ => return Err(Error::Type(Fault::default())),
+                    };
+                    elt.ok_or(Error::Range(Fault::default()))
+                })
             },
+            // Running a quotation takes the whole session, not just the
+            // stack, so `bytecode::run` evaluates these itself.
+            Op::Call | Op::Map | Op::Filter | Op::Fold => Err(Error::Type(Fault::default())),
             Op::Quit => Err(Error::Quit),
         }
     }
+
+    /// Copies the value `n` places below the top, where 0 is the top itself.
+    fn nth(&self, n: usize) -> Result<Elt> {
+        let len = self.values.len();
+        if n >= len {
+            return Err(Error::Underflow(Fault::default()));
+        }
+        Ok(self.values[len - 1 - n].clone())
+    }
+
+    /// Reads the depth argument of `pick` and `roll` from the top of the
+    /// stack, without popping it. The depth must be a non-negative integer
+    /// that reaches a value below the argument itself.
+    fn index(&self) -> Result<usize> {
+        match self.nth(0)? {
+            Elt::Int(n) if n >= 0 && (n as usize) + 1 < self.values.len() => Ok(n as usize),
+            Elt::Int(_) | Elt::Big(_) => Err(Error::Underflow(Fault::default())),
+            _ => Err(Error::Type(Fault::default())),
+        }
+    }
+
+    /// Moves the value `n` places below the top up to the top.
+    fn roll(&mut self, n: usize) -> Result<()> {
+        self.nth(n)?;
+        let len = self.values.len();
+        let elt = self.values.remove(len - 1 - n);
+        self.push(elt)
+    }
+
+    /// Pops two values x and y, returning `(y, x)`. Nothing is popped unless
+    /// both values are there.
+    fn pop2(&mut self) -> Result<(Elt, Elt)> {
+        if self.values.len() < 2 {
+            return Err(Error::Underflow(Fault::default()));
+        }
+        let elt_a = self.pop()?;
+        let elt_b = self.pop()?;
+        Ok((elt_b, elt_a))
+    }
+
+    /// Replaces the top value x with `f(x)`. If `f` fails, x stays in place.
+    fn unary<F>(&mut self, f: F) -> Result<()>
+        where F: FnOnce(&Elt) -> Result<Elt>
+    {
+        let top = self.values.last_mut().ok_or(Error::Underflow(Fault::default()))?;
+        *top = f(top)?;
+        Ok(())
+    }
+
+    /// Pops two values x and y, and pushes `f(y, x)`. If `f` fails, x and y
+    /// stay in place.
+    fn binary<F>(&mut self, f: F) -> Result<()>
+        where F: FnOnce(&Elt, &Elt) -> Result<Elt>
+    {
+        let (elt_b, elt_a) = self.pop2()?;
+        match f(&elt_b, &elt_a) {
+            Ok(res) => self.push(res),
+            Err(e) => {
+                self.push(elt_b)?;
+                self.push(elt_a)?;
+                Err(e)
+            },
+        }
+    }
+
+    /// Pops two numbers x and y, promotes them to a common type, and pushes
+    /// the result of `f(y, x)`. If `f` fails, x and y are put back.
+    fn num_binary<F>(&mut self, f: F) -> Result<()>
+        where F: FnOnce(Operands) -> Result<Elt>
+    {
+        let (elt_b, elt_a) = self.pop2()?;
+        match Operands::promote(&elt_b, &elt_a).ok_or(Error::Type(Fault::default())).and_then(f) {
+            Ok(res) => self.push(res),
+            Err(e) => {
+                self.push(elt_b)?;
+                self.push(elt_a)?;
+                Err(e)
+            },
+        }
+    }
+
+    /// Runs the integer, big integer, rational or floating-point version of
+    /// an arithmetic operation, depending on the promoted type of the top two
+    /// values. In `Arith::Bignum` mode, integer operations that overflow are
+    /// redone on big integers. Rationals are exact, so their overflow is an
+    /// error in every mode.
+    fn arithmetic<I, B, R, F>(&mut self, int: I, big: B, rational: R, float: F) -> Result<()>
+        where I: FnOnce(i32, i32) -> Result<i32>,
+              B: FnOnce(&BigInt, &BigInt) -> Option<BigInt>,
+              R: FnOnce(Rational, Rational) -> Option<Rational>,
+              F: FnOnce(f64, f64) -> f64
+    {
+        let arith = self.arith;
+        self.num_binary(|operands| {
+            match operands {
+                Operands::Int(y, x) => {
+                    match int(y, x) {
+                        Err(Error::Overflow) if arith == Arith::Bignum => {
+                            let (y, x) = (BigInt::from_i64(y as i64), BigInt::from_i64(x as i64));
+                            big(&y, &x).map(Elt::from_big).ok_or(Error::DivideByZero)
+                        },
+                        res => res.map(Elt::Int),
+                    }
+                },
+                Operands::Big(y, x) => big(&y, &x).map(Elt::from_big).ok_or(Error::DivideByZero),
+                Operands::Rational(y, x) => rational(y, x).map(Elt::Rational).ok_or(Error::Overflow),
+                Operands::Float(y, x) => Ok(Elt::Float(float(y, x))),
+            }
+        })
+    }
+
+    /// Pops two numbers x and y, and pushes whether `f` accepts how y
+    /// compares to x. Comparisons involving NaN are always false.
+    fn compare<F>(&mut self, f: F) -> Result<()>
+        where F: FnOnce(Ordering) -> bool
+    {
+        self.num_binary(|operands| Ok(Elt::Bool(operands.cmp().is_some_and(f))))
+    }
+
+    /// Fails with `Error::DivideByZero` if the top value is a zero.
+    fn check_divisor(&self) -> Result<()> {
+        match self.values.last() {
+            Some(&Elt::Int(0)) => Err(Error::DivideByZero),
+            Some(&Elt::Float(0.0)) => Err(Error::DivideByZero),
+            Some(&Elt::Rational(x)) if x.numer() == 0 => Err(Error::DivideByZero),
+            _ => Ok(()),
+        }
+    }
+
+    /// Pops two booleans x and y, and pushes the result of `f(y, x)`.
+    fn bool_binary<F>(&mut self, f: F) -> Result<()>
+        where F: FnOnce(bool, bool) -> bool
+    {
+        let (elt_b, elt_a) = self.pop2()?;
+        match (elt_b, elt_a) {
+            (Elt::Bool(y), Elt::Bool(x)) => self.push(Elt::Bool(f(y, x))),
+            (elt_b, elt_a) => {
+                self.push(elt_b)?;
+                self.push(elt_a)?;
+                Err(Error::Type(Fault::default()))
+            },
+        }
+    }
+}
+
+/// Two numeric operands y and x, promoted to a common type.
+enum Operands {
+    Int(i32, i32),
+    Big(BigInt, BigInt),
+    Rational(Rational, Rational),
+    Float(f64, f64),
+}
+
+impl Operands {
+    /// Promotes two values to a common numeric type: integers widen to big
+    /// integers or rationals, big integers mixed with rationals become
+    /// floats, and anything mixed with a float becomes a float. Returns
+    /// `None` if either value is not a number.
+    fn promote(y: &Elt, x: &Elt) -> Option<Operands> {
+        match (y, x) {
+            (&Elt::Int(y), &Elt::Int(x)) => Some(Operands::Int(y, x)),
+            (&Elt::Float(_), _) | (_, &Elt::Float(_)) |
+            (&Elt::Big(_), &Elt::Rational(_)) | (&Elt::Rational(_), &Elt::Big(_)) => {
+                Some(Operands::Float(y.to_f64()?, x.to_f64()?))
+            },
+            (&Elt::Big(_), _) | (_, &Elt::Big(_)) => {
+                Some(Operands::Big(y.to_big()?, x.to_big()?))
+            },
+            _ => Some(Operands::Rational(y.to_rational()?, x.to_rational()?)),
+        }
+    }
+
+    /// How y compares to x, or `None` if either is NaN.
+    fn cmp(&self) -> Option<Ordering> {
+        match *self {
+            Operands::Int(y, x) => Some(y.cmp(&x)),
+            Operands::Big(ref y, ref x) => Some(y.cmp(x)),
+            Operands::Rational(y, x) => Some(y.cmp(&x)),
+            Operands::Float(y, x) => y.partial_cmp(&x),
+        }
+    }
+}
+
+/// Raises y to the power x. Integer powers of integers stay exact, following
+/// the `Arith` mode on overflow; negative integer exponents give rationals.
+/// Anything else is computed in floating point.
+fn pow(operands: Operands, arith: Arith) -> Result<Elt> {
+    match operands {
+        Operands::Int(y, x) if x >= 0 => {
+            let exp = x as u32;
+            match arith.pick(y.checked_pow(exp), y.wrapping_pow(exp), y.saturating_pow(exp)) {
+                Err(Error::Overflow) if arith == Arith::Bignum => {
+                    big_pow(&BigInt::from_i64(y as i64), exp)
+                },
+                res => res.map(Elt::Int),
+            }
+        },
+        Operands::Int(y, x) => rational_pow(Rational::from_int(y as i64), x as i64),
+        Operands::Big(ref y, ref x) => {
+            match x.to_i32() {
+                Some(exp) if exp >= 0 => big_pow(y, exp as u32),
+                _ => Err(Error::Overflow),
+            }
+        },
+        Operands::Rational(y, x) if x.denom() == 1 => rational_pow(y, x.numer()),
+        Operands::Rational(y, x) => Ok(Elt::Float(y.to_f64().powf(x.to_f64()))),
+        Operands::Float(y, x) => Ok(Elt::Float(y.powf(x))),
+    }
+}
+
+/// Raises a big integer to a power, refusing results over `MAX_POW_BITS`.
+fn big_pow(y: &BigInt, exp: u32) -> Result<Elt> {
+    if y.bits() > 1 && y.bits().saturating_mul(exp as u64) > MAX_POW_BITS {
+        return Err(Error::Overflow);
+    }
+    Ok(Elt::from_big(y.pow(exp)))
+}
+
+/// Raises a fraction to an integer power, by repeated squaring.
+fn rational_pow(y: Rational, exp: i64) -> Result<Elt> {
+    if exp < 0 && y.numer() == 0 {
+        return Err(Error::DivideByZero);
+    }
+    let mut base = if exp < 0 {
+        Rational::from_int(1).checked_div(y).ok_or(Error::Overflow)?
+    } else {
+        y
+    };
+    let mut exp = exp.unsigned_abs();
+    let mut acc = Rational::from_int(1);
+    while exp > 0 {
+        if exp & 1 == 1 {
+            acc = acc.checked_mul(base).ok_or(Error::Overflow)?;
+        }
+        exp >>= 1;
+        if exp > 0 {
+            base = base.checked_mul(base).ok_or(Error::Overflow)?;
+        }
+    }
+    Ok(Elt::Rational(acc))
+}
+
+/// Checks two values for equality, comparing numbers of different types by
+/// their value.
+fn equal(y: &Elt, x: &Elt) -> bool {
+    match Operands::promote(y, x) {
+        Some(operands) => operands.cmp() == Some(Ordering::Equal),
+        None => y == x,
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use bigint::BigInt;
+    use rational::Rational;
 
     #[test]
     fn test_pop_empty1() {
@@ -119,7 +1184,7 @@ mod tests {
 
         let res = s.pop();
         assert!(res.is_err());
-        if let Err(Error::Underflow) = res { } else { assert!(false); }
+        if let Err(Error::Underflow(_)) = res { } else { panic!(); }
     }
 
     #[test]
@@ -132,7 +1197,7 @@ mod tests {
 
         let res = s.pop();
         assert!(res.is_err());
-        if let Err(Error::Underflow) = res { } else { assert!(false); }
+        if let Err(Error::Underflow(_)) = res { } else { panic!(); }
     }
 
     #[test]
@@ -153,7 +1218,7 @@ mod tests {
 
         let res = s.eval(Op::Add);
         assert!(res.is_err());
-        if let Err(Error::Type) = res { } else { assert!(false); }
+        if let Err(Error::Type(_)) = res { } else { panic!(); }
     }
 
     #[test]
@@ -164,7 +1229,7 @@ mod tests {
 
         let res = s.eval(Op::Add);
         assert!(res.is_err());
-        if let Err(Error::Type) = res { } else { assert!(false); }
+        if let Err(Error::Type(_)) = res { } else { panic!(); }
     }
 
     #[test]
@@ -185,7 +1250,7 @@ mod tests {
 
         let res = s.eval(Op::Add);
         assert!(res.is_err());
-        if let Err(Error::Type) = res { } else { assert!(false); }
+        if let Err(Error::Type(_)) = res { } else { panic!(); }
     }
 
     #[test]
@@ -216,7 +1281,7 @@ mod tests {
 
         let res = s.pop();
         assert!(res.is_err());
-        if let Err(Error::Underflow) = res { } else { assert!(false); }
+        if let Err(Error::Underflow(_)) = res { } else { panic!(); }
     }
 
     #[test]
@@ -226,18 +1291,359 @@ mod tests {
 
         let res = s.eval(Op::Swap);
         assert!(res.is_err());
-        if let Err(Error::Underflow) = res { } else { assert!(false); }
+        if let Err(Error::Underflow(_)) = res { } else { panic!(); }
     }
 
-    #[test]
-    fn test_eval_rand1() {
+    /// Pushes `elts`, bottom first, evaluates `op`, and returns the stack.
+    fn eval_on(elts: &[Elt], op: Op) -> Result<Vec<Elt>> {
         let mut s = Stack::new();
-        let i = 20;
-        s.push(Elt::Int(i)).unwrap();
-
-        assert!(s.eval(Op::Rand).is_ok());
+        for elt in elts {
+            s.push(elt.clone()).unwrap();
+        }
+        s.eval(op).map(|_| s.values().to_vec())
+    }
 
-        let rand_val = s.pop().unwrap();
+    #[test]
+    fn test_eval_stack_words1() {
+        let (x, y, z) = (Elt::Int(1), Elt::Bool(false), Elt::Float(2.5));
+        let cases = [
+            (Op::Dup, vec![x.clone()], vec![x.clone(), x.clone()]),
+            (Op::Drop, vec![x.clone(), y.clone()], vec![x.clone()]),
+            (Op::Over, vec![x.clone(), y.clone()], vec![x.clone(), y.clone(), x.clone()]),
+            (Op::Rot, vec![x.clone(), y.clone(), z.clone()], vec![y.clone(), z.clone(), x.clone()]),
+            (Op::RotBack, vec![x.clone(), y.clone(), z.clone()], vec![z.clone(), x.clone(), y.clone()]),
+            (Op::Nip, vec![x.clone(), y.clone()], vec![y.clone()]),
+            (Op::Tuck, vec![x.clone(), y.clone()], vec![y.clone(), x.clone(), y.clone()]),
+            (Op::Clear, vec![x.clone(), y.clone()], vec![]),
+            (Op::Clear, vec![], vec![]),
+            (Op::Depth, vec![x.clone(), y.clone()], vec![x.clone(), y.clone(), Elt::Int(2)]),
+            (Op::Depth, vec![], vec![Elt::Int(0)]),
+        ];
+        for &(op, ref before, ref after) in cases.iter() {
+            assert_eq!(eval_on(before, op).unwrap(), *after, "{:?}", op);
+        }
+    }
+
+    #[test]
+    fn test_eval_stack_words2() {
+        let x = Elt::Int(1);
+        let cases = [
+            (Op::Dup, vec![]),
+            (Op::Drop, vec![]),
+            (Op::Over, vec![x.clone()]),
+            (Op::Rot, vec![x.clone(), x.clone()]),
+            (Op::RotBack, vec![x.clone(), x.clone()]),
+            (Op::Nip, vec![x.clone()]),
+            (Op::Tuck, vec![x.clone()]),
+        ];
+        for &(op, ref before) in cases.iter() {
+            let mut s = Stack::new();
+            for elt in before {
+                s.push(elt.clone()).unwrap();
+            }
+            let res = s.eval(op);
+            if let Err(Error::Underflow(ref fault)) = res {
+                assert_eq!(fault.op, op.token());
+            } else { panic!("{:?}", op); }
+            assert_eq!(s.values(), &before[..]);
+        }
+    }
+
+    #[test]
+    fn test_eval_dup1() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+
+        assert!(s.eval(Op::Dup).is_ok());
+        assert_eq!(s.values(), &[Elt::Int(1), Elt::Int(1)]);
+    }
+
+    #[test]
+    fn test_eval_dup2() {
+        let mut s = Stack::new();
+
+        let res = s.eval(Op::Dup);
+        if let Err(Error::Underflow(ref fault)) = res {
+            assert_eq!(*fault, Fault { op: "dup".to_string(), stack: vec![] });
+        } else { panic!(); }
+        assert!(s.values().is_empty());
+    }
+
+    #[test]
+    fn test_eval_drop1() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Bool(false)).unwrap();
+
+        assert!(s.eval(Op::Drop).is_ok());
+        assert_eq!(s.values(), &[Elt::Int(1)]);
+    }
+
+    #[test]
+    fn test_eval_drop2() {
+        let mut s = Stack::new();
+
+        let res = s.eval(Op::Drop);
+        if let Err(Error::Underflow(ref fault)) = res {
+            assert_eq!(*fault, Fault { op: "drop".to_string(), stack: vec![] });
+        } else { panic!(); }
+        assert!(s.values().is_empty());
+    }
+
+    #[test]
+    fn test_eval_over1() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Bool(false)).unwrap();
+
+        assert!(s.eval(Op::Over).is_ok());
+        assert_eq!(s.values(), &[Elt::Int(1), Elt::Bool(false), Elt::Int(1)]);
+    }
+
+    #[test]
+    fn test_eval_over2() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+
+        let res = s.eval(Op::Over);
+        if let Err(Error::Underflow(ref fault)) = res {
+            assert_eq!(*fault, Fault { op: "over".to_string(), stack: vec![Elt::Int(1)] });
+        } else { panic!(); }
+        assert_eq!(s.values(), &[Elt::Int(1)]);
+    }
+
+    #[test]
+    fn test_eval_rot1() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Int(2)).unwrap();
+        s.push(Elt::Int(3)).unwrap();
+
+        assert!(s.eval(Op::Rot).is_ok());
+        assert_eq!(s.values(), &[Elt::Int(2), Elt::Int(3), Elt::Int(1)]);
+    }
+
+    #[test]
+    fn test_eval_rot2() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Int(2)).unwrap();
+
+        let res = s.eval(Op::Rot);
+        if let Err(Error::Underflow(ref fault)) = res {
+            assert_eq!(*fault, Fault { op: "rot".to_string(), stack: vec![Elt::Int(1), Elt::Int(2)] });
+        } else { panic!(); }
+        assert_eq!(s.values(), &[Elt::Int(1), Elt::Int(2)]);
+    }
+
+    #[test]
+    fn test_eval_rot_back1() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Int(2)).unwrap();
+        s.push(Elt::Int(3)).unwrap();
+
+        assert!(s.eval(Op::RotBack).is_ok());
+        assert_eq!(s.values(), &[Elt::Int(3), Elt::Int(1), Elt::Int(2)]);
+    }
+
+    #[test]
+    fn test_eval_rot_back2() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Int(2)).unwrap();
+
+        let res = s.eval(Op::RotBack);
+        if let Err(Error::Underflow(ref fault)) = res {
+            assert_eq!(*fault, Fault { op: "-rot".to_string(), stack: vec![Elt::Int(1), Elt::Int(2)] });
+        } else { panic!(); }
+        assert_eq!(s.values(), &[Elt::Int(1), Elt::Int(2)]);
+    }
+
+    #[test]
+    fn test_eval_nip1() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Bool(false)).unwrap();
+
+        assert!(s.eval(Op::Nip).is_ok());
+        assert_eq!(s.values(), &[Elt::Bool(false)]);
+    }
+
+    #[test]
+    fn test_eval_nip2() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+
+        let res = s.eval(Op::Nip);
+        if let Err(Error::Underflow(ref fault)) = res {
+            assert_eq!(*fault, Fault { op: "nip".to_string(), stack: vec![Elt::Int(1)] });
+        } else { panic!(); }
+        assert_eq!(s.values(), &[Elt::Int(1)]);
+    }
+
+    #[test]
+    fn test_eval_tuck1() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Bool(false)).unwrap();
+
+        assert!(s.eval(Op::Tuck).is_ok());
+        assert_eq!(s.values(), &[Elt::Bool(false), Elt::Int(1), Elt::Bool(false)]);
+    }
+
+    #[test]
+    fn test_eval_tuck2() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+
+        let res = s.eval(Op::Tuck);
+        if let Err(Error::Underflow(ref fault)) = res {
+            assert_eq!(*fault, Fault { op: "tuck".to_string(), stack: vec![Elt::Int(1)] });
+        } else { panic!(); }
+        assert_eq!(s.values(), &[Elt::Int(1)]);
+    }
+
+    #[test]
+    fn test_eval_clear() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Bool(false)).unwrap();
+
+        assert!(s.eval(Op::Clear).is_ok());
+        assert!(s.values().is_empty());
+        // There is nothing to underflow.
+        assert!(s.eval(Op::Clear).is_ok());
+        assert!(s.values().is_empty());
+    }
+
+    #[test]
+    fn test_eval_depth() {
+        let mut s = Stack::new();
+
+        assert!(s.eval(Op::Depth).is_ok());
+        assert_eq!(s.values(), &[Elt::Int(0)]);
+        s.push(Elt::Bool(false)).unwrap();
+        assert!(s.eval(Op::Depth).is_ok());
+        assert_eq!(s.values(), &[Elt::Int(0), Elt::Bool(false), Elt::Int(2)]);
+    }
+
+    #[test]
+    fn test_eval_strings_lists() {
+        let s = |s: &str| Elt::Str(s.to_string());
+        let list = Elt::List(vec![Elt::Int(1), s("a")]);
+        assert_eq!(eval_on(&[s("ab"), s("cd")], Op::Concat).unwrap(), vec![s("abcd")]);
+        assert_eq!(eval_on(&[list.clone(), Elt::List(vec![Elt::Bool(true)])], Op::Concat).unwrap(),
+                   vec![Elt::List(vec![Elt::Int(1), s("a"), Elt::Bool(true)])]);
+        assert_eq!(eval_on(&[s("héllo")], Op::Length).unwrap(), vec![Elt::Int(5)]);
+        assert_eq!(eval_on(&[s("héllo"), Elt::Int(1)], Op::Index).unwrap(), vec![s("é")]);
+        assert_eq!(eval_on(&[list.clone(), Elt::Int(1)], Op::Index).unwrap(), vec![s("a")]);
+        assert_eq!(list.to_string(), "{ 1 \"a\" }");
+        assert_eq!(eval_on(&[list], Op::Length).unwrap(), vec![Elt::Int(2)]);
+        assert_eq!(s("say \"hi\"\n").to_string(), "\"say \\\"hi\\\"\\n\"");
+    }
+
+    #[test]
+    fn test_eval_strings_lists_errors() {
+        let mut s = Stack::new();
+        s.push(Elt::Str("ab".to_string())).unwrap();
+        s.push(Elt::Int(2)).unwrap();
+        if let Err(Error::Range(_)) = s.eval(Op::Index) { } else { panic!(); }
+        assert_eq!(s.values().len(), 2);
+        if let Err(Error::Type(_)) = s.eval(Op::Concat) { } else { panic!(); }
+        if let Err(Error::Type(_)) = s.eval(Op::Length) { } else { panic!(); }
+        assert_eq!(s.values(), &[Elt::Str("ab".to_string()), Elt::Int(2)]);
+    }
+
+    #[test]
+    fn test_eval_pick1() {
+        let elts = [Elt::Int(10), Elt::Int(20), Elt::Int(30)];
+        let mut with = elts.to_vec();
+        with.push(Elt::Int(0));
+        assert_eq!(eval_on(&with, Op::Pick).unwrap(), vec![Elt::Int(10), Elt::Int(20), Elt::Int(30), Elt::Int(30)]);
+        with[3] = Elt::Int(2);
+        assert_eq!(eval_on(&with, Op::Pick).unwrap(), vec![Elt::Int(10), Elt::Int(20), Elt::Int(30), Elt::Int(10)]);
+    }
+
+    #[test]
+    fn test_eval_pick2() {
+        let mut s = Stack::new();
+        let res = s.eval(Op::Pick);
+        if let Err(Error::Underflow(ref fault)) = res {
+            assert_eq!(*fault, Fault { op: "pick".to_string(), stack: vec![] });
+        } else { panic!(); }
+
+        s.push(Elt::Int(10)).unwrap();
+        // `n` counts the values below it, so 1 is out of range here, as is
+        // anything negative.
+        for n in &[1, 5, -1, i32::MIN] {
+            s.push(Elt::Int(*n)).unwrap();
+            let res = s.eval(Op::Pick);
+            if let Err(Error::Underflow(ref fault)) = res {
+                assert_eq!(*fault, Fault { op: "pick".to_string(), stack: vec![Elt::Int(10), Elt::Int(*n)] });
+            } else { panic!("{}", n); }
+            assert_eq!(s.values(), &[Elt::Int(10), Elt::Int(*n)]);
+            s.pop().unwrap();
+        }
+
+        s.push(Elt::Bool(true)).unwrap();
+        let res = s.eval(Op::Pick);
+        if let Err(Error::Type(ref fault)) = res {
+            assert_eq!(fault.op, "pick");
+        } else { panic!(); }
+        assert_eq!(s.values(), &[Elt::Int(10), Elt::Bool(true)]);
+    }
+
+    #[test]
+    fn test_eval_roll1() {
+        let elts = [Elt::Int(10), Elt::Int(20), Elt::Int(30)];
+        let mut with = elts.to_vec();
+        with.push(Elt::Int(0));
+        assert_eq!(eval_on(&with, Op::Roll).unwrap(), elts.to_vec());
+        with[3] = Elt::Int(1);
+        assert_eq!(eval_on(&with, Op::Roll).unwrap(), vec![Elt::Int(10), Elt::Int(30), Elt::Int(20)]);
+        with[3] = Elt::Int(2);
+        assert_eq!(eval_on(&with, Op::Roll).unwrap(), vec![Elt::Int(20), Elt::Int(30), Elt::Int(10)]);
+    }
+
+    #[test]
+    fn test_eval_roll2() {
+        let mut s = Stack::new();
+        let res = s.eval(Op::Roll);
+        if let Err(Error::Underflow(ref fault)) = res {
+            assert_eq!(*fault, Fault { op: "roll".to_string(), stack: vec![] });
+        } else { panic!(); }
+
+        s.push(Elt::Int(10)).unwrap();
+        s.push(Elt::Int(20)).unwrap();
+        for n in &[2, 100, -1, i32::MIN] {
+            s.push(Elt::Int(*n)).unwrap();
+            let res = s.eval(Op::Roll);
+            if let Err(Error::Underflow(ref fault)) = res {
+                assert_eq!(*fault, Fault { op: "roll".to_string(),
+                                           stack: vec![Elt::Int(10), Elt::Int(20), Elt::Int(*n)] });
+            } else { panic!("{}", n); }
+            assert_eq!(s.values(), &[Elt::Int(10), Elt::Int(20), Elt::Int(*n)]);
+            s.pop().unwrap();
+        }
+
+        s.push(Elt::Float(1.0)).unwrap();
+        let res = s.eval(Op::Roll);
+        if let Err(Error::Type(ref fault)) = res {
+            assert_eq!(fault.op, "roll");
+        } else { panic!(); }
+        assert_eq!(s.values(), &[Elt::Int(10), Elt::Int(20), Elt::Float(1.0)]);
+    }
+
+    #[test]
+    fn test_eval_rand1() {
+        let mut s = Stack::with_seed(42);
+        let i = 20;
+        s.push(Elt::Int(i)).unwrap();
+
+        assert!(s.eval(Op::Rand).is_ok());
+
+        let rand_val = s.pop().unwrap();
         assert!(rand_val >= Elt::Int(0));
         assert!(rand_val < Elt::Int(i));
     }
@@ -249,7 +1655,47 @@ mod tests {
 
         let res = s.eval(Op::Rand);
         assert!(res.is_err());
-        if let Err(Error::Type) = res { } else { assert!(false); }
+        if let Err(Error::Type(_)) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_eval_rand3() {
+        for &i in &[0, -5] {
+            let mut s = Stack::new();
+            s.push(Elt::Int(i)).unwrap();
+
+            let res = s.eval(Op::Rand);
+            if let Err(Error::Range(ref fault)) = res {
+                assert_eq!(fault.op, "#");
+            } else { panic!(); }
+            assert_eq!(s.values(), &[Elt::Int(i)]);
+        }
+    }
+
+    #[test]
+    fn test_eval_seed() {
+        let draw = |s: &mut Stack| -> Vec<Elt> {
+            (0..8).map(|_| {
+                s.push(Elt::Int(1000)).unwrap();
+                s.eval(Op::Rand).unwrap();
+                s.pop().unwrap()
+            }).collect()
+        };
+        let mut s = Stack::with_seed(7);
+        let first = draw(&mut s);
+        s.push(Elt::Int(7)).unwrap();
+        assert!(s.eval(Op::Seed).is_ok());
+        assert_eq!(draw(&mut s), first);
+        assert!(s.values().is_empty());
+
+        let snapshot = s.snapshot();
+        let next = draw(&mut s);
+        s.restore(snapshot);
+        assert_eq!(draw(&mut s), next);
+
+        s.push(Elt::Float(1.5)).unwrap();
+        let res = s.eval(Op::Seed);
+        if let Err(Error::Type(_)) = res { } else { panic!(); }
     }
 
     #[test]
@@ -258,6 +1704,575 @@ mod tests {
 
         let res = s.eval(Op::Quit);
         assert!(res.is_err());
-        if let Err(Error::Quit) = res { } else { assert!(false); }
+        if let Err(Error::Quit) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_eval_sub1() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(5)).unwrap();
+        s.push(Elt::Int(3)).unwrap();
+
+        assert!(s.eval(Op::Sub).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(2));
+    }
+
+    #[test]
+    fn test_eval_sub2() {
+        let mut s = Stack::new();
+        s.push(Elt::Bool(true)).unwrap();
+        s.push(Elt::Int(3)).unwrap();
+
+        let res = s.eval(Op::Sub);
+        assert!(res.is_err());
+        if let Err(Error::Type(_)) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_eval_mul1() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(-4)).unwrap();
+        s.push(Elt::Int(3)).unwrap();
+
+        assert!(s.eval(Op::Mul).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(-12));
+    }
+
+    #[test]
+    fn test_eval_div1() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(7)).unwrap();
+        s.push(Elt::Int(2)).unwrap();
+
+        assert!(s.eval(Op::Div).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(3));
+    }
+
+    #[test]
+    fn test_eval_div2() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(7)).unwrap();
+        s.push(Elt::Int(0)).unwrap();
+
+        let res = s.eval(Op::Div);
+        assert!(res.is_err());
+        if let Err(Error::DivideByZero) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_eval_rem1() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(7)).unwrap();
+        s.push(Elt::Int(2)).unwrap();
+
+        assert!(s.eval(Op::Rem).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(1));
+    }
+
+    #[test]
+    fn test_eval_rem2() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(7)).unwrap();
+        s.push(Elt::Int(0)).unwrap();
+
+        let res = s.eval(Op::Rem);
+        assert!(res.is_err());
+        if let Err(Error::DivideByZero) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_eval_ne1() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Bool(true)).unwrap();
+
+        assert!(s.eval(Op::Ne).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Bool(true));
+    }
+
+    #[test]
+    fn test_eval_cmp1() {
+        let mut s = Stack::new();
+        for &(op, res) in &[(Op::Lt, true), (Op::Le, true), (Op::Gt, false), (Op::Ge, false)] {
+            s.push(Elt::Int(1)).unwrap();
+            s.push(Elt::Int(2)).unwrap();
+            assert!(s.eval(op).is_ok());
+            assert_eq!(s.pop().unwrap(), Elt::Bool(res));
+        }
+        for &(op, res) in &[(Op::Lt, false), (Op::Le, true), (Op::Gt, false), (Op::Ge, true)] {
+            s.push(Elt::Int(2)).unwrap();
+            s.push(Elt::Int(2)).unwrap();
+            assert!(s.eval(op).is_ok());
+            assert_eq!(s.pop().unwrap(), Elt::Bool(res));
+        }
+    }
+
+    #[test]
+    fn test_eval_cmp2() {
+        let mut s = Stack::new();
+        s.push(Elt::Bool(false)).unwrap();
+        s.push(Elt::Bool(true)).unwrap();
+
+        let res = s.eval(Op::Lt);
+        assert!(res.is_err());
+        if let Err(Error::Type(_)) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_eval_and_or1() {
+        let mut s = Stack::new();
+        s.push(Elt::Bool(true)).unwrap();
+        s.push(Elt::Bool(false)).unwrap();
+        assert!(s.eval(Op::And).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Bool(false));
+
+        s.push(Elt::Bool(true)).unwrap();
+        s.push(Elt::Bool(false)).unwrap();
+        assert!(s.eval(Op::Or).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Bool(true));
+    }
+
+    #[test]
+    fn test_eval_and_or2() {
+        let mut s = Stack::new();
+        s.push(Elt::Bool(true)).unwrap();
+        s.push(Elt::Int(1)).unwrap();
+
+        let res = s.eval(Op::And);
+        assert!(res.is_err());
+        if let Err(Error::Type(_)) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_eval_not1() {
+        let mut s = Stack::new();
+        s.push(Elt::Bool(false)).unwrap();
+        assert!(s.eval(Op::Not).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Bool(true));
+    }
+
+    #[test]
+    fn test_eval_not2() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+
+        let res = s.eval(Op::Not);
+        assert!(res.is_err());
+        if let Err(Error::Type(_)) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_eval_overflow1() {
+        let mut s = Stack::new();
+        s.set_arith(Arith::Checked);
+        s.push(Elt::Int(i32::MAX)).unwrap();
+        s.push(Elt::Int(1)).unwrap();
+
+        let res = s.eval(Op::Add);
+        assert!(res.is_err());
+        if let Err(Error::Overflow) = res { } else { panic!(); }
+        assert_eq!(s.pop().unwrap(), Elt::Int(1));
+        assert_eq!(s.pop().unwrap(), Elt::Int(i32::MAX));
+    }
+
+    #[test]
+    fn test_eval_overflow2() {
+        let mut s = Stack::new();
+        s.set_arith(Arith::Checked);
+        for &(a, b, op) in &[(i32::MIN, 1, Op::Sub), (i32::MAX, 2, Op::Mul),
+                             (i32::MIN, -1, Op::Div), (i32::MIN, -1, Op::Rem)] {
+            s.push(Elt::Int(a)).unwrap();
+            s.push(Elt::Int(b)).unwrap();
+            let res = s.eval(op);
+            if let Err(Error::Overflow) = res { } else { panic!("{:?}", op); }
+            assert_eq!(s.pop().unwrap(), Elt::Int(b));
+            assert_eq!(s.pop().unwrap(), Elt::Int(a));
+        }
+
+        s.push(Elt::Int(i32::MIN)).unwrap();
+        let res = s.eval(Op::Neg);
+        if let Err(Error::Overflow) = res { } else { panic!(); }
+        assert_eq!(s.pop().unwrap(), Elt::Int(i32::MIN));
+    }
+
+    #[test]
+    fn test_eval_wrapping() {
+        let mut s = Stack::new();
+        s.set_arith(Arith::Wrapping);
+        s.push(Elt::Int(i32::MAX)).unwrap();
+        s.push(Elt::Int(1)).unwrap();
+
+        assert!(s.eval(Op::Add).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(i32::MIN));
+
+        s.push(Elt::Int(i32::MIN)).unwrap();
+        assert!(s.eval(Op::Neg).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(i32::MIN));
+    }
+
+    #[test]
+    fn test_eval_saturating() {
+        let mut s = Stack::new();
+        s.set_arith(Arith::Saturating);
+        s.push(Elt::Int(i32::MIN)).unwrap();
+        s.push(Elt::Int(2)).unwrap();
+
+        assert!(s.eval(Op::Mul).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(i32::MIN));
+
+        s.push(Elt::Int(i32::MIN)).unwrap();
+        s.push(Elt::Int(-1)).unwrap();
+        assert!(s.eval(Op::Div).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(i32::MAX));
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        let snapshot = s.snapshot();
+
+        s.push(Elt::Bool(true)).unwrap();
+        s.set_arith(Arith::Wrapping);
+        assert!(s.eval(Op::Add).is_err());
+
+        s.restore(snapshot);
+        assert_eq!(s.arith(), Arith::Bignum);
+        assert_eq!(s.pop().unwrap(), Elt::Int(1));
+        assert!(s.pop().is_err());
+    }
+
+    #[test]
+    fn test_eval_promote1() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Rational(Rational::new(1, 2).unwrap())).unwrap();
+        assert!(s.eval(Op::Add).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Rational(Rational::new(3, 2).unwrap()));
+
+        s.push(Elt::Rational(Rational::new(1, 2).unwrap())).unwrap();
+        s.push(Elt::Float(0.25)).unwrap();
+        assert!(s.eval(Op::Mul).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Float(0.125));
+
+        s.push(Elt::Int(3)).unwrap();
+        s.push(Elt::Float(0.5)).unwrap();
+        assert!(s.eval(Op::Sub).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Float(2.5));
+    }
+
+    #[test]
+    fn test_eval_promote2() {
+        let mut s = Stack::new();
+        s.push(Elt::Float(1.5)).unwrap();
+        s.push(Elt::Bool(true)).unwrap();
+
+        let res = s.eval(Op::Add);
+        assert!(res.is_err());
+        if let Err(Error::Type(_)) = res { } else { panic!(); }
+        assert_eq!(s.pop().unwrap(), Elt::Bool(true));
+        assert_eq!(s.pop().unwrap(), Elt::Float(1.5));
+    }
+
+    #[test]
+    fn test_eval_float_div() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(7)).unwrap();
+        s.push(Elt::Float(2.0)).unwrap();
+        assert!(s.eval(Op::Div).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Float(3.5));
+
+        s.push(Elt::Float(1.0)).unwrap();
+        s.push(Elt::Float(0.0)).unwrap();
+        let res = s.eval(Op::Div);
+        if let Err(Error::DivideByZero) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_eval_rational_div() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(7)).unwrap();
+        s.push(Elt::Rational(Rational::new(2, 3).unwrap())).unwrap();
+        assert!(s.eval(Op::Div).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Rational(Rational::new(21, 2).unwrap()));
+
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Rational(Rational::from_int(0))).unwrap();
+        let res = s.eval(Op::Rem);
+        if let Err(Error::DivideByZero) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_eval_rational_overflow() {
+        let mut s = Stack::new();
+        s.set_arith(Arith::Wrapping);
+        s.push(Elt::Rational(Rational::from_int(i64::MAX))).unwrap();
+        s.push(Elt::Int(2)).unwrap();
+
+        let res = s.eval(Op::Mul);
+        if let Err(Error::Overflow) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_eval_mixed_cmp() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Float(1.0)).unwrap();
+        assert!(s.eval(Op::Eq).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Bool(true));
+
+        s.push(Elt::Rational(Rational::new(1, 3).unwrap())).unwrap();
+        s.push(Elt::Float(0.5)).unwrap();
+        assert!(s.eval(Op::Lt).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Bool(true));
+
+        s.push(Elt::Float(f64::NAN)).unwrap();
+        s.push(Elt::Float(f64::NAN)).unwrap();
+        assert!(s.eval(Op::Ge).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Bool(false));
+    }
+
+    #[test]
+    fn test_eval_neg_float_rational() {
+        let mut s = Stack::new();
+        s.push(Elt::Float(1.5)).unwrap();
+        assert!(s.eval(Op::Neg).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Float(-1.5));
+
+        s.push(Elt::Rational(Rational::new(1, 2).unwrap())).unwrap();
+        assert!(s.eval(Op::Neg).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Rational(Rational::new(-1, 2).unwrap()));
+    }
+
+    #[test]
+    fn test_eval_convert1() {
+        let mut s = Stack::new();
+        s.push(Elt::Float(-2.75)).unwrap();
+        assert!(s.eval(Op::ToInt).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(-2));
+
+        s.push(Elt::Int(3)).unwrap();
+        assert!(s.eval(Op::ToFloat).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Float(3.0));
+
+        s.push(Elt::Float(1.5)).unwrap();
+        assert!(s.eval(Op::ToRational).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Rational(Rational::new(3, 2).unwrap()));
+    }
+
+    #[test]
+    fn test_eval_convert2() {
+        let mut s = Stack::new();
+        s.push(Elt::Float(1e10)).unwrap();
+        let res = s.eval(Op::ToInt);
+        if let Err(Error::Overflow) = res { } else { panic!(); }
+        assert_eq!(s.pop().unwrap(), Elt::Float(1e10));
+
+        s.push(Elt::Bool(true)).unwrap();
+        let res = s.eval(Op::ToFloat);
+        if let Err(Error::Type(_)) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Elt::Int(-3).to_string(), "-3");
+        assert_eq!(Elt::Bool(true).to_string(), "true");
+        assert_eq!(Elt::Float(2.0).to_string(), "2.0");
+        assert_eq!(Elt::Rational(Rational::new(7, 3).unwrap()).to_string(), "7/3");
+    }
+
+    fn big(s: &str) -> Elt {
+        Elt::Big(s.parse().unwrap())
+    }
+
+    #[test]
+    fn test_eval_bignum1() {
+        let mut s = Stack::new();
+        s.set_arith(Arith::Bignum);
+        s.push(Elt::Int(i32::MAX)).unwrap();
+        s.push(Elt::Int(i32::MAX)).unwrap();
+        assert!(s.eval(Op::Mul).is_ok());
+        assert_eq!(s.pop().unwrap(), big("4611686014132420609"));
+
+        s.push(Elt::Int(i32::MIN)).unwrap();
+        assert!(s.eval(Op::Neg).is_ok());
+        assert_eq!(s.pop().unwrap(), big("2147483648"));
+
+        s.push(Elt::Int(i32::MIN)).unwrap();
+        s.push(Elt::Int(-1)).unwrap();
+        assert!(s.eval(Op::Div).is_ok());
+        assert_eq!(s.pop().unwrap(), big("2147483648"));
+    }
+
+    #[test]
+    fn test_eval_bignum2() {
+        let mut s = Stack::new();
+        s.push(big("4611686014132420609")).unwrap();
+        s.push(Elt::Int(i32::MAX)).unwrap();
+        assert!(s.eval(Op::Div).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(i32::MAX));
+
+        s.push(big("4611686014132420610")).unwrap();
+        s.push(Elt::Int(i32::MAX)).unwrap();
+        assert!(s.eval(Op::Rem).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(1));
+
+        s.push(big("4611686014132420610")).unwrap();
+        s.push(big("4611686014132420609")).unwrap();
+        assert!(s.eval(Op::Sub).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(1));
+    }
+
+    #[test]
+    fn test_eval_bignum_cmp() {
+        let mut s = Stack::new();
+        s.push(big("4611686014132420609")).unwrap();
+        s.push(Elt::Int(1)).unwrap();
+        assert!(s.eval(Op::Gt).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Bool(true));
+
+        s.push(big("4294967296")).unwrap();
+        s.push(Elt::Float(4294967296.0)).unwrap();
+        assert!(s.eval(Op::Eq).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Bool(true));
+    }
+
+    #[test]
+    fn test_eval_pow1() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(3)).unwrap();
+        s.push(Elt::Int(4)).unwrap();
+        assert!(s.eval(Op::Pow).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(81));
+
+        s.push(Elt::Int(2)).unwrap();
+        s.push(Elt::Int(-2)).unwrap();
+        assert!(s.eval(Op::Pow).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Rational(Rational::new(1, 4).unwrap()));
+
+        s.push(Elt::Float(4.0)).unwrap();
+        s.push(Elt::Float(0.5)).unwrap();
+        assert!(s.eval(Op::Pow).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Float(2.0));
+    }
+
+    #[test]
+    fn test_eval_pow2() {
+        let mut s = Stack::new();
+        s.set_arith(Arith::Checked);
+        s.push(Elt::Int(2)).unwrap();
+        s.push(Elt::Int(31)).unwrap();
+        let res = s.eval(Op::Pow);
+        if let Err(Error::Overflow) = res { } else { panic!(); }
+
+        s.set_arith(Arith::Bignum);
+        assert!(s.eval(Op::Pow).is_ok());
+        assert_eq!(s.pop().unwrap(), big("2147483648"));
+
+        s.push(Elt::Int(2)).unwrap();
+        s.push(Elt::Int(i32::MAX)).unwrap();
+        let res = s.eval(Op::Pow);
+        if let Err(Error::Overflow) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_display_big() {
+        assert_eq!(big("-123456789012345678901234567890").to_string(),
+                   "-123456789012345678901234567890");
+        assert_eq!(Elt::from_big(BigInt::from_i64(5)), Elt::Int(5));
+    }
+
+    #[test]
+    fn test_eval_fault() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Bool(true)).unwrap();
+
+        let res = s.eval(Op::Add);
+        if let Err(Error::Type(ref fault)) = res {
+            assert_eq!(fault.op, "+");
+            assert_eq!(fault.stack, vec![Elt::Int(1), Elt::Bool(true)]);
+        } else { panic!(); }
+        assert_eq!(res.unwrap_err().to_string(), "type error in `+` with stack [1 true]");
+
+        s.pop().unwrap();
+        let res = s.eval(Op::Swap);
+        if let Err(Error::Underflow(ref fault)) = res {
+            assert_eq!(fault.op, "<->");
+            assert_eq!(fault.stack, vec![Elt::Int(1)]);
+        } else { panic!(); }
+    }
+
+    #[test]
+    fn test_undo() {
+        let mut s = Stack::new();
+        let mut undo = Undo::new(2);
+        for i in 1..4 {
+            let before = s.snapshot();
+            s.push(Elt::Int(i)).unwrap();
+            undo.record(before, &s);
+        }
+        let before = s.snapshot();
+        undo.record(before, &s);
+        assert_eq!(undo.position(), (2, 0));
+
+        assert!(undo.undo(&mut s));
+        assert!(undo.undo(&mut s));
+        assert!(!undo.undo(&mut s));
+        assert_eq!(s.values(), &[Elt::Int(1)]);
+        assert_eq!(undo.position(), (0, 2));
+
+        assert!(undo.redo(&mut s));
+        assert_eq!(s.values(), &[Elt::Int(1), Elt::Int(2)]);
+        let before = s.snapshot();
+        s.set_arith(Arith::Wrapping);
+        undo.record(before, &s);
+        assert_eq!(undo.position(), (2, 0));
+        assert!(!undo.redo(&mut s));
+
+        undo.set_depth(1);
+        assert_eq!(undo.position(), (1, 0));
+        assert!(undo.undo(&mut s));
+        assert_eq!(s.arith(), Arith::Bignum);
+        assert_eq!(s.values(), &[Elt::Int(1), Elt::Int(2)]);
+    }
+
+    #[test]
+    fn test_vars() {
+        let mut vars = Vars::new();
+        vars.store("x", Elt::Int(42));
+        vars.store("0", Elt::Bool(true));
+        assert_eq!(vars.fetch("x").unwrap(), Elt::Int(42));
+        vars.store("x", Elt::Float(1.5));
+        assert_eq!(vars.fetch("x").unwrap(), Elt::Float(1.5));
+        assert_eq!(vars.list().collect::<Vec<_>>(),
+                   vec![("0", &Elt::Bool(true)), ("x", &Elt::Float(1.5))]);
+
+        assert_eq!(vars.delete("x").unwrap(), Elt::Float(1.5));
+        let res = vars.fetch("x");
+        if let Err(Error::Unbound(ref name)) = res {
+            assert_eq!(name, "x");
+        } else { panic!(); }
+        assert_eq!(res.unwrap_err().to_string(), "unbound variable `x`");
+        let res = vars.delete("x");
+        if let Err(Error::Unbound(_)) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_op_tokens() {
+        assert_eq!(Op::from_token("<="), Some(Op::Le));
+        assert_eq!(Op::from_token("<=>"), None);
+        assert_eq!(Op::ToRational.token(), ">rat");
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut s = Stack::new();
+        let res = s.peek();
+        if let Err(Error::Underflow(_)) = res { } else { panic!(); }
+
+        s.push(Elt::Int(1)).unwrap();
+        assert_eq!(*s.peek().unwrap(), Elt::Int(1));
+        assert_eq!(s.values(), &[Elt::Int(1)]);
     }
 }
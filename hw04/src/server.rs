@@ -0,0 +1,138 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use interpreter::Interpreter;
+use parser::{evaluate_line, Session};
+use rpn;
+
+/// A line for the shared session, and where to send the reply to it.
+type Request = (String, Sender<Option<String>>);
+
+/// Serves the calculator to each connection from `incoming` until it runs
+/// out, reading a line per request and writing a line per reply (see
+/// `reply`). Every connection gets an interpreter of its own from
+/// `new_interpreter`, run on the connection's own thread, or, if `shared`,
+/// they all use the one interpreter.
+///
+/// Interpreters aren't `Send`, so the shared one lives on a thread of its
+/// own, and each connection's thread passes its lines on to it. Its lines
+/// are evaluated one at a time, so a line never sees another connection's
+/// half done, but a line that never finishes holds up every connection.
+pub fn serve<I, S, F>(incoming: I, shared: bool, new_interpreter: F)
+    where I: Iterator<Item = io::Result<S>>,
+          S: Send + 'static,
+          for<'a> &'a S: Read + Write,
+          F: Fn() -> Interpreter + Send + Sync + 'static
+{
+    let new_interpreter = Arc::new(new_interpreter);
+    let requests = if shared { Some(run_shared(new_interpreter.clone())) } else { None };
+    for stream in incoming {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                continue;
+            },
+        };
+        let requests = requests.clone();
+        let new_interpreter = new_interpreter.clone();
+        thread::spawn(move || {
+            let res = match requests {
+                Some(requests) => {
+                    let (reply_to, replies) = mpsc::channel();
+                    converse(&stream, |line| {
+                        requests.send((line, reply_to.clone())).ok()?;
+                        replies.recv().ok()?
+                    })
+                },
+                None => {
+                    let mut session = new_interpreter().session;
+                    converse(&stream, |line| reply(&mut session, &line))
+                },
+            };
+            if let Err(err) = res {
+                eprintln!("Error: {}", err);
+            }
+        });
+    }
+}
+
+/// Starts the thread that makes and runs the shared interpreter, returning
+/// where to send it lines.
+fn run_shared<F>(new_interpreter: Arc<F>) -> Sender<Request>
+    where F: Fn() -> Interpreter + Send + Sync + 'static
+{
+    let (requests, received) = mpsc::channel::<Request>();
+    thread::spawn(move || {
+        let mut session = new_interpreter().session;
+        for (line, reply_to) in received {
+            let _ = reply_to.send(reply(&mut session, &line));
+        }
+    });
+    requests
+}
+
+/// Reads lines from a connection, and writes back what `reply` makes of
+/// each, until either side hangs up.
+fn converse<S, F>(stream: &S, mut reply: F) -> io::Result<()>
+    where for<'a> &'a S: Read + Write, F: FnMut(String) -> Option<String>
+{
+    let mut writer = stream;
+    for line in BufReader::new(stream).lines() {
+        match reply(line?) {
+            Some(reply) => writeln!(writer, "{}", reply)?,
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+/// Evaluates a line, and says how it went: `ok` and the stack, e.g.
+/// `ok [1 2]`, or `error`, the kind of error and what went wrong, e.g.
+/// `error underflow stack underflow in ...`. A line that fails changes
+/// nothing, as in the REPL. `quit` gives `None`, to hang up.
+pub fn reply(session: &mut Session, line: &str) -> Option<String> {
+    match evaluate_line(session, line) {
+        Ok(()) => Some(format!("ok {}", session.stack)),
+        Err(rpn::Error::Quit) => None,
+        Err(err) => Some(format!("error {} {}", err.kind(), err)),
+    }
+}
+
+/// Sends each line of `input` to the server on `stream`, and writes its
+/// replies to `output`, until the input ends or the server hangs up.
+pub fn client<S, R, W>(stream: &S, input: R, output: &mut W) -> io::Result<()>
+    where for<'a> &'a S: Read + Write, R: BufRead, W: Write
+{
+    let mut replies = BufReader::new(stream).lines();
+    let mut writer = stream;
+    for line in input.lines() {
+        writeln!(writer, "{}", line?)?;
+        match replies.next() {
+            Some(reply) => writeln!(output, "{}", reply?)?,
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::Session;
+    use server::reply;
+
+    #[test]
+    fn test_reply() {
+        let mut session = Session::new();
+        assert_eq!(reply(&mut session, "1 2"), Some("ok [1 2]".to_string()));
+        assert_eq!(reply(&mut session, "\"a b\" : sq dup * ;"), Some("ok [1 2 \"a b\"]".to_string()));
+        assert_eq!(reply(&mut session, "drop + + 3 sq"),
+                   Some("error underflow stack underflow in `+` with stack [3]".to_string()));
+        assert_eq!(reply(&mut session, "nope"),
+                   Some("error syntax syntax error at column 1: unknown word `nope`".to_string()));
+        assert_eq!(reply(&mut session, ""), Some("ok [1 2 \"a b\"]".to_string()));
+        assert_eq!(reply(&mut session, "quit"), None);
+    }
+}
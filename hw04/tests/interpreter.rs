@@ -0,0 +1,224 @@
+extern crate hw04;
+
+use std::rc::Rc;
+
+use hw04::{Effect, Elt, Error, Interpreter, Op, Operator, Result, Stack, Ty};
+
+#[test]
+fn test_eval_str() {
+    let mut interp = Interpreter::new();
+    assert_eq!(interp.eval_str("1 2 +").unwrap(), vec![Elt::Int(3)]);
+    assert_eq!(interp.eval_str(": sq dup * ;\n4 sq\n\"a\" \"b\" concat").unwrap(),
+               vec![Elt::Int(3), Elt::Int(16), Elt::Str("ab".to_string())]);
+    // Definitions and variables last from one call to the next.
+    assert_eq!(interp.eval_str("clear 5 !x\n@x sq").unwrap(), vec![Elt::Int(25)]);
+    assert_eq!(interp.eval_str("1\nquit\n2").unwrap(), vec![Elt::Int(25), Elt::Int(1)]);
+    assert_eq!(interp.stack(), &[Elt::Int(25), Elt::Int(1)]);
+}
+
+#[test]
+fn test_eval_str_errors() {
+    let mut interp = Interpreter::new();
+    // The failing line is undone, and the lines before it are kept.
+    if let Err(Error::Underflow(fault)) = interp.eval_str("1 2\n3 + + +") {
+        assert_eq!(fault.op, "+");
+        assert_eq!(fault.stack, vec![Elt::Int(6)]);
+    } else {
+        panic!();
+    }
+    assert_eq!(interp.stack(), &[Elt::Int(1), Elt::Int(2)]);
+    if let Err(Error::Undefined(_)) = interp.eval_str(": f nope ;") {
+    } else {
+        panic!();
+    }
+    if let Err(Error::Syntax(_)) = interp.eval_str("nope") {
+    } else {
+        panic!();
+    }
+}
+
+#[test]
+fn test_register() {
+    let mut interp = Interpreter::new();
+    interp.register("double", |stack| {
+        match stack.pop()? {
+            Elt::Int(n) => stack.push(Elt::Int(n * 2)),
+            _ => Err(Error::Type(Default::default())),
+        }
+    }).unwrap();
+    interp.register("hello", |stack| stack.push(Elt::Str("hello".to_string()))).unwrap();
+    assert_eq!(interp.eval_str("21 double hello").unwrap(),
+               vec![Elt::Int(42), Elt::Str("hello".to_string())]);
+    // Native words can be used like any other.
+    assert_eq!(interp.eval_str("clear : quad double double ;\n{ 1 2 } [ quad ] map").unwrap(),
+               vec![Elt::List(vec![Elt::Int(4), Elt::Int(8)])]);
+    // Natives aren't saved with the session, but words using them are.
+    let mut saved = Vec::new();
+    interp.save(&mut saved).unwrap();
+    let text = String::from_utf8(saved.clone()).unwrap();
+    assert!(text.contains(": quad double double ;"));
+    assert!(!text.contains(": double"));
+    // Loading keeps the operators, so words using them load back.
+    interp.eval_str("clear : inc 1 + ;").unwrap();
+    interp.load(&saved[..]).unwrap();
+    assert_eq!(interp.eval_str("clear 3 quad 1 double").unwrap(), vec![Elt::Int(12), Elt::Int(2)]);
+    if let Err(Error::Syntax(_)) = interp.eval_str("inc") {
+    } else {
+        panic!();
+    }
+}
+
+#[test]
+fn test_register_errors() {
+    let mut interp = Interpreter::new();
+    interp.register("halve", |stack| {
+        match stack.pop()? {
+            Elt::Int(n) => stack.push(Elt::Int(n / 2)),
+            _ => Err(Error::Type(Default::default())),
+        }
+    }).unwrap();
+    // Errors are blamed on the word, with the stack from before it ran.
+    if let Err(Error::Type(fault)) = interp.eval_str("1 true halve") {
+        assert_eq!(fault.op, "halve");
+        assert_eq!(fault.stack, vec![Elt::Int(1), Elt::Bool(true)]);
+    } else {
+        panic!();
+    }
+    if let Err(Error::Underflow(fault)) = interp.eval_str("halve") {
+        assert_eq!(fault.op, "halve");
+    } else {
+        panic!();
+    }
+    assert_eq!(interp.stack(), &[] as &[Elt]);
+    interp.eval_str(": inc 1 + ;").unwrap();
+    for name in &["", "1", "@x", "=x", "if", "undo", "two words", "inc"] {
+        if let Err(Error::Syntax(_)) = interp.register(name, |_| Ok(())) {
+        } else {
+            panic!("registered `{}`", name);
+        }
+    }
+}
+
+/// Clamps an integer between two others, which are pushed after it.
+struct Clamp;
+
+impl Operator for Clamp {
+    fn name(&self) -> &str {
+        "clamp"
+    }
+
+    fn arity(&self) -> usize {
+        3
+    }
+
+    fn effect(&self) -> Option<Effect> {
+        Some(Effect::new(&[Ty::Int, Ty::Int, Ty::Int], &[Ty::Int]))
+    }
+
+    fn apply(&self, stack: &mut Stack) -> Result<()> {
+        let (hi, lo, x) = (stack.pop()?, stack.pop()?, stack.pop()?);
+        match (x, lo, hi) {
+            (Elt::Int(x), Elt::Int(lo), Elt::Int(hi)) => stack.push(Elt::Int(x.max(lo).min(hi))),
+            _ => Err(Error::Type(Default::default())),
+        }
+    }
+}
+
+/// A built-in operator under another name.
+struct Alias(&'static str, Rc<dyn Operator>);
+
+impl Alias {
+    fn new(name: &'static str, token: &str) -> Alias {
+        Alias(name, Rc::new(Op::from_token(token).unwrap()))
+    }
+}
+
+impl Operator for Alias {
+    fn name(&self) -> &str {
+        self.0
+    }
+
+    fn arity(&self) -> usize {
+        self.1.arity()
+    }
+
+    fn apply(&self, stack: &mut Stack) -> Result<()> {
+        self.1.apply(stack)
+    }
+}
+
+#[test]
+fn test_register_op() {
+    let mut interp = Interpreter::new();
+    interp.register_op(Clamp).unwrap();
+    assert_eq!(interp.eval_str("15 0 10 clamp -5 0 10 clamp 5 0 10 clamp").unwrap(),
+               vec![Elt::Int(10), Elt::Int(0), Elt::Int(5)]);
+    if let Err(Error::Underflow(fault)) = interp.eval_str("clear 1 2 clamp") {
+        assert_eq!(fault.op, "clamp");
+        assert_eq!(fault.stack, vec![Elt::Int(1), Elt::Int(2)]);
+    } else {
+        panic!();
+    }
+    // The checker knows what the operator takes and leaves.
+    assert_eq!(interp.effect("0 100 clamp").unwrap().unwrap().to_string(), "( int -- int )");
+    if let Err(Error::Check(_)) = interp.effect("true 0 1 clamp") {
+    } else {
+        panic!();
+    }
+}
+
+#[test]
+fn test_replace_builtin() {
+    let mut interp = Interpreter::new();
+    interp.eval_str(": add3 + + ;").unwrap();
+    // Words already defined keep the operator they were compiled with.
+    let old = interp.unregister("+").unwrap();
+    assert_eq!(old.name(), "+");
+    assert_eq!(old.builtin(), Some(Op::Add));
+    assert_eq!(interp.eval_str("1 2 3 add3").unwrap(), vec![Elt::Int(6)]);
+    if let Err(Error::Syntax(_)) = interp.eval_str("1 +") {
+    } else {
+        panic!();
+    }
+    interp.register_op(Alias::new("+", "*")).unwrap();
+    assert_eq!(interp.eval_str("clear 6 7 +").unwrap(), vec![Elt::Int(42)]);
+    // Registering replaces operators, built-in or not.
+    interp.register_op(Alias::new("-", "+")).unwrap();
+    interp.register_op(Alias::new("+", "-")).unwrap();
+    assert_eq!(interp.eval_str("clear 6 7 - 1 +").unwrap(), vec![Elt::Int(12)]);
+    // With `!=` gone, `!=` reads as a store to `=`, which can't be a variable.
+    interp.unregister("!=");
+    if let Err(Error::Syntax(err)) = interp.eval_str("1 2 !=") {
+        assert_eq!(err.message, "can't name a variable `=`");
+    } else {
+        panic!();
+    }
+}
+
+#[test]
+fn test_scripts() {
+    let mut interp = Interpreter::new();
+    interp.register_op(Clamp).unwrap();
+    let script = "#!/usr/bin/env hw04\n: sq dup * ;\n7 sq 0 10 clamp\n";
+    let mut output = Vec::new();
+    interp.run_script(script.as_bytes(), &mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "10\n");
+    assert_eq!(interp.check_script("1 sq\n".as_bytes()).unwrap(), Some(vec![Ty::Int, Ty::Num]));
+    let err = interp.check_script("1\n\"a\" sq\n".as_bytes()).unwrap_err();
+    assert_eq!(err.line, 2);
+    if let Error::Check(_) = err.error {
+    } else {
+        panic!();
+    }
+    let mut output = Vec::new();
+    interp.repl("2 +\n".as_bytes(), &mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), ">   12\n> \n");
+    // The same seed gives the same numbers.
+    let mut rolls = Vec::new();
+    for _ in 0..2 {
+        let mut interp = Interpreter::new();
+        interp.set_seed(42);
+        rolls.push(interp.eval_str("100 # 100 #").unwrap());
+    }
+    assert_eq!(rolls[0], rolls[1]);
+}
@@ -0,0 +1,125 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::process::{Child, ChildStderr, Command, Stdio};
+
+/// A `hw04 --serve` process, killed when dropped.
+struct Server {
+    child: Child,
+    addr: String,
+    /// Kept open, so that the server can still report errors.
+    _stderr: BufReader<ChildStderr>,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Starts a server with `args`, and waits until it is listening.
+fn start(args: &[&str]) -> Server {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_hw04"))
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let mut line = String::new();
+    stderr.read_line(&mut line).unwrap();
+    let addr = line.trim().trim_start_matches("listening on ").to_string();
+    Server { child, addr, _stderr: stderr }
+}
+
+/// Starts a server on a free local port.
+fn start_tcp(shared: bool) -> Server {
+    let mut args = vec!["--serve", "127.0.0.1:0"];
+    if shared {
+        args.push("--shared");
+    }
+    start(&args)
+}
+
+/// Sends lines on a connection, then hangs up and returns the replies.
+fn talk<S: Read + Write>(mut stream: S, lines: &str, hang_up: fn(&S)) -> String {
+    stream.write_all(lines.as_bytes()).unwrap();
+    hang_up(&stream);
+    let mut output = String::new();
+    stream.read_to_string(&mut output).unwrap();
+    output
+}
+
+/// Sends lines to the server at `addr` on a new connection, returning its
+/// replies.
+fn send(addr: &str, lines: &str) -> String {
+    talk(TcpStream::connect(addr).unwrap(), lines, |s| s.shutdown(Shutdown::Write).unwrap())
+}
+
+#[test]
+fn test_serve() {
+    let server = start_tcp(false);
+    assert_eq!(send(&server.addr, "1 2\n: sq dup * ;\n+ sq\n+\n"),
+               concat!("ok [1 2]\n",
+                       "ok [1 2]\n",
+                       "ok [9]\n",
+                       "error underflow stack underflow in `+` with stack [9]\n"));
+    // Each connection starts afresh.
+    assert_eq!(send(&server.addr, "3 sq\n"), "error syntax syntax error at column 3: unknown word `sq`\n");
+    // The server hangs up on `quit`.
+    assert_eq!(send(&server.addr, "1\nquit\n2\n"), "ok [1]\n");
+}
+
+#[test]
+fn test_serve_busy() {
+    let server = start_tcp(false);
+    // A line that never finishes holds up only its own connection.
+    let mut busy = TcpStream::connect(&server.addr).unwrap();
+    writeln!(busy, "begin false until").unwrap();
+    assert_eq!(send(&server.addr, "1 2 +\n"), "ok [3]\n");
+}
+
+#[test]
+fn test_serve_nested() {
+    let server = start_tcp(false);
+    // Lines nested too deeply to run are refused, and the server keeps
+    // going.
+    let lines = format!("1\n{}\n={}1\n2\n", "[ ".repeat(20_000), "(".repeat(20_000));
+    let replies = send(&server.addr, &lines);
+    let replies: Vec<&str> = replies.lines().collect();
+    assert_eq!(replies.len(), 4);
+    assert_eq!(replies[0], "ok [1]");
+    assert!(replies[1].starts_with("error syntax "));
+    assert!(replies[2].starts_with("error syntax "));
+    assert_eq!(replies[3], "ok [1 2]");
+    assert_eq!(send(&server.addr, "3\n"), "ok [3]\n");
+}
+
+#[test]
+fn test_serve_shared() {
+    let server = start_tcp(true);
+    assert_eq!(send(&server.addr, "1 : inc 1 + ;\n"), "ok [1]\n");
+    assert_eq!(send(&server.addr, "inc\n"), "ok [2]\n");
+    let mut other = BufReader::new(TcpStream::connect(&server.addr).unwrap());
+    writeln!(other.get_mut(), "inc").unwrap();
+    let mut reply = String::new();
+    other.read_line(&mut reply).unwrap();
+    assert_eq!(reply, "ok [3]\n");
+    assert_eq!(send(&server.addr, "inc\n"), "ok [4]\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_serve_unix() {
+    use std::os::unix::net::UnixStream;
+    use std::{env, fs, process};
+
+    let path = env::temp_dir().join(format!("hw04-test-{}.sock", process::id()));
+    let _ = fs::remove_file(&path);
+    let server = start(&["--unix", path.to_str().unwrap()]);
+    let stream = UnixStream::connect(&server.addr).unwrap();
+    let output = talk(stream, "2 3 **\n\"a\" 1 +\n", |s| s.shutdown(Shutdown::Write).unwrap());
+    let _ = fs::remove_file(&path);
+    assert_eq!(output, "ok [8]\nerror type type error in `+` with stack [8 \"a\" 1]\n");
+}